@@ -0,0 +1,203 @@
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{SecondsFormat, Utc};
+use http_body::{Frame, SizeHint};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::mpsc,
+    time::Instant,
+};
+
+use crate::proxy::ClientIp;
+
+#[derive(Deserialize)]
+pub struct AccessLog {
+    /// File to append JSON lines to, or `-` for stdout.
+    path: PathBuf,
+}
+
+/// How a request for a store path was answered.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Decision {
+    Mirror,
+    S3,
+    Origin,
+    Miss,
+    /// Not found by the origins that answered, while another one failed.
+    PartialMiss,
+    CachedNegative,
+    Peer,
+}
+
+/// Attached to responses by handlers so that the access log can record the
+/// decision behind them.
+#[derive(Clone, Default)]
+pub struct Outcome {
+    pub decision: Option<Decision>,
+    /// Host of the upstream the response came from or redirects to.
+    pub upstream: Option<String>,
+    /// Whether the request started an upload to S3.
+    pub upload: bool,
+}
+
+#[derive(Serialize)]
+struct Line {
+    time: String,
+    method: String,
+    path: String,
+    client_ip: Option<IpAddr>,
+    status: u16,
+    bytes: u64,
+    duration_ms: f64,
+    decision: Option<Decision>,
+    upstream: Option<String>,
+    upload: bool,
+}
+
+/// Writes one JSON line per response once its body has been sent.
+#[derive(Clone)]
+pub struct AccessLogger {
+    tx: Option<mpsc::UnboundedSender<String>>,
+}
+
+impl AccessLogger {
+    pub async fn open(config: Option<&AccessLog>) -> anyhow::Result<Self> {
+        let Some(config) = config else {
+            return Ok(Self { tx: None });
+        };
+        let out: Pin<Box<dyn AsyncWrite + Send>> = if config.path == Path::new("-") {
+            Box::pin(tokio::io::stdout())
+        } else {
+            Box::pin(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&config.path)
+                    .await?,
+            )
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(write_lines(BufWriter::new(out), rx));
+        Ok(Self { tx: Some(tx) })
+    }
+}
+
+/// Flushes whenever the queue is empty so that lines are written in batches
+/// under load but never held back when idle.
+async fn write_lines<W: AsyncWrite + Unpin>(mut out: W, mut rx: mpsc::UnboundedReceiver<String>) {
+    while let Some(line) = rx.recv().await {
+        let mut res = out.write_all(line.as_bytes()).await;
+        while let Ok(line) = rx.try_recv() {
+            if res.is_ok() {
+                res = out.write_all(line.as_bytes()).await;
+            }
+        }
+        if res.is_ok() {
+            res = out.flush().await;
+        }
+        if let Err(err) = res {
+            tracing::error!("cannot write access log: {:?}", err);
+        }
+    }
+}
+
+pub async fn record(State(log): State<AccessLogger>, request: Request, next: Next) -> Response {
+    let Some(tx) = log.tx else {
+        return next.run(request).await;
+    };
+    let client_ip = request.extensions().get::<ClientIp>().map(|c| c.0);
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let start = Instant::now();
+    let response = next.run(request).await;
+
+    let outcome = response
+        .extensions()
+        .get::<Outcome>()
+        .cloned()
+        .unwrap_or_default();
+    let line = Line {
+        time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        method,
+        path,
+        client_ip,
+        status: response.status().as_u16(),
+        bytes: 0,
+        duration_ms: 0.0,
+        decision: outcome.decision,
+        upstream: outcome.upstream,
+        upload: outcome.upload,
+    };
+    let (parts, body) = response.into_parts();
+    Response::from_parts(
+        parts,
+        Body::new(LoggedBody {
+            inner: body,
+            line: Some(line),
+            start,
+            tx,
+        }),
+    )
+}
+
+/// Counts the bytes of a response body and logs the request when dropped.
+struct LoggedBody {
+    inner: Body,
+    line: Option<Line>,
+    start: Instant,
+    tx: mpsc::UnboundedSender<String>,
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let res = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &res
+            && let Some(data) = frame.data_ref()
+            && let Some(line) = &mut self.line
+        {
+            line.bytes += data.len() as u64;
+        }
+        res
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        let Some(mut line) = self.line.take() else {
+            return;
+        };
+        line.duration_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+        if let Ok(mut json) = serde_json::to_string(&line) {
+            json.push('\n');
+            let _ = self.tx.send(json);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::net::IpAddr;
+
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+use metrics::counter;
+use serde::Deserialize;
+
+use crate::AppState;
+use crate::proxy::ClientIp;
+
+/// Network ACL for store paths. A missing list allows every address.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Acl {
+    /// Networks allowed to GET and HEAD.
+    get: Option<Vec<IpNet>>,
+    /// Networks allowed to PUT.
+    put: Option<Vec<IpNet>>,
+    /// Networks allowed to DELETE.
+    delete: Option<Vec<IpNet>>,
+    /// Networks denied every method, regardless of the lists above.
+    deny: Vec<IpNet>,
+}
+
+impl Acl {
+    pub fn allows(&self, method: &Method, ip: IpAddr) -> bool {
+        let contains = |nets: &[IpNet]| nets.iter().any(|net| net.contains(&ip));
+        if contains(&self.deny) {
+            return false;
+        }
+        let list = match *method {
+            Method::GET | Method::HEAD => &self.get,
+            Method::PUT => &self.put,
+            Method::DELETE => &self.delete,
+            _ => return true,
+        };
+        list.as_deref().is_none_or(contains)
+    }
+}
+
+/// Rejects requests from networks the ACL does not allow for their method.
+pub async fn enforce(
+    State(app): State<AppState>,
+    ClientIp(ip): ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !app.acl_allows(request.method(), ip) {
+        counter!("nix_store_gateway_acl_denied", "method" => request.method().to_string())
+            .increment(1);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(config: &str) -> Acl {
+        toml::from_str(config).unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn missing_lists_allow_everyone() {
+        let acl = acl("");
+        for method in [Method::GET, Method::HEAD, Method::PUT, Method::DELETE] {
+            assert!(acl.allows(&method, ip("192.0.2.1")));
+            assert!(acl.allows(&method, ip("2001:db8::1")));
+        }
+    }
+
+    #[test]
+    fn each_method_uses_its_own_list() {
+        let acl = acl(r#"
+            get = ["10.0.0.0/8", "2001:db8::/32"]
+            put = ["10.20.0.0/16"]
+            delete = []
+        "#);
+        let cases = [
+            (Method::GET, "10.1.2.3", true),
+            (Method::HEAD, "10.1.2.3", true),
+            (Method::GET, "2001:db8::1", true),
+            (Method::GET, "192.0.2.1", false),
+            (Method::HEAD, "192.0.2.1", false),
+            (Method::PUT, "10.20.0.1", true),
+            (Method::PUT, "10.1.2.3", false),
+            (Method::DELETE, "10.20.0.1", false),
+            // Methods without a list are left to the router.
+            (Method::POST, "192.0.2.1", true),
+        ];
+        for (method, addr, allowed) in cases {
+            assert_eq!(acl.allows(&method, ip(addr)), allowed, "{method} {addr}");
+        }
+    }
+
+    #[test]
+    fn deny_overrides_allow() {
+        let acl = acl(r#"
+            get = ["10.0.0.0/8"]
+            deny = ["10.66.0.0/16", "192.0.2.1/32"]
+        "#);
+        assert!(acl.allows(&Method::GET, ip("10.1.2.3")));
+        assert!(!acl.allows(&Method::GET, ip("10.66.1.1")));
+        // deny applies to methods without a list, too.
+        assert!(!acl.allows(&Method::PUT, ip("10.66.1.1")));
+        assert!(!acl.allows(&Method::POST, ip("192.0.2.1")));
+        assert!(acl.allows(&Method::PUT, ip("192.0.2.2")));
+    }
+}
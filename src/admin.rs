@@ -0,0 +1,105 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, Request, State},
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::AppState;
+
+pub fn router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/cache", axum::routing::delete(invalidate))
+        .route("/cache/{*key}", get(inspect).delete(invalidate_key))
+        .route("/probe/{*key}", post(probe))
+        .route("/uploads", get(uploads))
+        .route_layer(middleware::from_fn_with_state(state, authorize))
+}
+
+/// Rejects requests without the configured bearer token. The API is hidden
+/// entirely when no token is configured.
+async fn authorize(State(app): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(token) = app.admin_token() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let provided = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    // Compare digests so the comparison time does not depend on the token.
+    match provided {
+        Some(provided) if Sha256::digest(provided) == Sha256::digest(&token) => {
+            next.run(request).await
+        }
+        _ => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct InvalidateQuery {
+    prefix: Option<String>,
+}
+
+async fn invalidate(State(app): State<AppState>, Query(query): Query<InvalidateQuery>) -> Response {
+    if let Some(prefix) = query.prefix {
+        let prefix = format!("/{}", prefix.trim_start_matches('/'));
+        let removed = app.invalidate_prefix(&prefix).await;
+        Json(json!({ "removed": removed })).into_response()
+    } else {
+        app.invalidate_all();
+        StatusCode::NO_CONTENT.into_response()
+    }
+}
+
+async fn invalidate_key(State(app): State<AppState>, Path(key): Path<String>) -> Response {
+    let path = format!("/{key}");
+    if !app.key_allowed(&path) {
+        return (StatusCode::BAD_REQUEST, "invalid store key\n").into_response();
+    }
+    if let Err(err) = app.invalidate(&path).await {
+        return err.into_response();
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn inspect(State(app): State<AppState>, Path(key): Path<String>) -> Response {
+    let path = format!("/{key}");
+    if !app.key_allowed(&path) {
+        return (StatusCode::BAD_REQUEST, "invalid store key\n").into_response();
+    }
+    let provenance = match app.provenance(&path).await {
+        Ok(v) => v,
+        Err(err) => {
+            tracing::warn!("{} provenance error: {:?}", path, err);
+            None
+        }
+    };
+    Json(json!({
+        "key": path,
+        "item": app.lookup(&path).await,
+        "provenance": provenance,
+    }))
+    .into_response()
+}
+
+async fn probe(State(app): State<AppState>, Path(key): Path<String>) -> Response {
+    let path = format!("/{key}");
+    if !app.key_allowed(&path) {
+        return (StatusCode::BAD_REQUEST, "invalid store key\n").into_response();
+    }
+    let item = match app.probe(&path).await {
+        Ok(item) => item,
+        Err(err) => return err.into_response(),
+    };
+    Json(json!({ "key": path, "item": item })).into_response()
+}
+
+async fn uploads(State(app): State<AppState>) -> Response {
+    Json(app.uploads()).into_response()
+}
@@ -1,18 +1,74 @@
-use std::{future::Future, path::Path, time::Duration};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
+use arc_swap::ArcSwap;
 use bytes::Bytes;
-use futures::Stream;
-use reqwest::{Client, Url, redirect::Policy};
-use serde::Deserialize;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::{Stream, stream::FuturesUnordered};
+use metrics::{counter, histogram};
+use reqwest::{
+    Client, Method, Url,
+    header::{HeaderMap, HeaderValue},
+    redirect::Policy,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{OwnedSemaphorePermit, mpsc},
+    time::Instant,
+};
+use tokio_stream::{StreamExt, wrappers::ReceiverStream};
+use tokio_util::{io::ReaderStream, sync::CancellationToken, task::TaskTracker};
+use tracing::Instrument;
 
+use crate::access_log::{AccessLog, Decision};
+use crate::acl::Acl;
+use crate::cache::{CacheItem, LookupCache, Ttls};
+use crate::cluster::{self, Cluster, PeerHealth};
+use crate::error::{self, Error};
+use crate::headers::Headers;
+use crate::key::{KeyFilter, Keys};
+use crate::limit::{Limiter, Limits};
+use crate::nix::{self, Write};
+use crate::proxy::Proxy;
 use crate::sign::AwsSigner;
+use crate::telemetry::{self, Telemetry};
+use crate::upload::{Spool, Upload, UploadQueue};
 
 #[derive(Deserialize)]
 pub struct Config {
     mirrors: Vec<Mirror>,
     origins: Vec<Origin>,
     s3: S3,
+    #[serde(default)]
+    cache: Cache,
+    #[serde(default)]
+    upload: Upload,
+    #[serde(default)]
+    limits: Limits,
+    #[serde(default)]
+    proxy: Proxy,
+    #[serde(default)]
+    acl: Acl,
+    #[serde(default)]
+    write: Write,
+    #[serde(default)]
+    headers: Headers,
+    #[serde(default)]
+    keys: Keys,
+    cluster: Option<Cluster>,
+    admin: Option<Admin>,
+    telemetry: Option<Telemetry>,
+    access_log: Option<AccessLog>,
 }
 
 #[derive(Deserialize)]
@@ -23,6 +79,33 @@ struct Mirror {
 #[derive(Deserialize)]
 struct Origin {
     url: Url,
+    /// Redirects followed for a single fetch before giving up.
+    #[serde(default = "default_max_redirects")]
+    max_redirects: usize,
+    /// Follow redirects to hosts other than the origin's, such as its CDN.
+    #[serde(default = "default_cross_host_redirects")]
+    cross_host_redirects: bool,
+    /// Seconds to wait for the response headers of each request.
+    #[serde(default = "default_origin_timeout")]
+    timeout: u64,
+}
+
+impl Origin {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+fn default_max_redirects() -> usize {
+    5
+}
+
+fn default_cross_host_redirects() -> bool {
+    true
+}
+
+fn default_origin_timeout() -> u64 {
+    30
 }
 
 #[derive(Deserialize)]
@@ -34,36 +117,227 @@ struct S3 {
     access_key_secret: String,
 }
 
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+struct Cache {
+    /// Persist lookup results to this file so they survive restarts.
+    path: Option<PathBuf>,
+    /// Maximum number of lookup results kept in memory.
+    capacity: Option<u64>,
+    /// TTLs in seconds for each kind of lookup result.
+    mirror_ttl: u64,
+    origin_ttl: u64,
+    negative_mirror_ttl: u64,
+    negative_origin_ttl: u64,
+    /// Lifetime in seconds of presigned S3 URLs handed to clients.
+    presign_ttl: u64,
+    /// Record narinfos that no origin has as marker objects in S3, valid for
+    /// this many seconds, so that replicas sharing the bucket see them too.
+    negative_marker_ttl: Option<u64>,
+}
+
+impl Cache {
+    fn ttls(&self) -> Ttls {
+        Ttls {
+            mirror: Duration::from_secs(self.mirror_ttl),
+            origin: Duration::from_secs(self.origin_ttl),
+            not_exist_mirror: Duration::from_secs(self.negative_mirror_ttl),
+            not_exist_origin: Duration::from_secs(self.negative_origin_ttl),
+        }
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            path: None,
+            capacity: None,
+            mirror_ttl: 300,
+            origin_ttl: 300,
+            negative_mirror_ttl: 300,
+            negative_origin_ttl: 300,
+            presign_ttl: 1500,
+            negative_marker_ttl: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Admin {
+    /// Bearer token required by the `/_admin` API.
+    token: String,
+}
+
 impl Config {
     pub fn load(config: impl AsRef<Path>) -> anyhow::Result<Self> {
         let config = std::fs::read_to_string(config)?;
-        Ok(toml::from_str(&config)?)
+        let config: Self = toml::from_str(&config)?;
+        if config.upload.concurrency == 0 || config.upload.queue == 0 {
+            bail!("upload.concurrency and upload.queue must be positive");
+        }
+        config.limits.validate()?;
+        config.headers.validate()?;
+        // S3 rejects presigned URLs valid for longer than a week.
+        if !(1..=604_800).contains(&config.cache.presign_ttl) {
+            bail!("cache.presign_ttl must be between 1 and 604800 seconds");
+        }
+        Ok(config)
+    }
+
+    pub fn telemetry(&self) -> Option<&Telemetry> {
+        self.telemetry.as_ref()
+    }
+
+    pub fn access_log(&self) -> Option<&AccessLog> {
+        self.access_log.as_ref()
+    }
+
+    pub fn proxy(&self) -> &Proxy {
+        &self.proxy
+    }
+
+    /// Keeps lookup results in memory only.
+    pub fn disable_persistence(&mut self) {
+        self.cache.path = None;
     }
 }
 
-#[derive(Clone)]
-enum CacheItem {
-    Mirror(String),
-    Origin(String),
-    NotExistMirror,
-    NotExistOrigin,
+/// Where an object stored in S3 came from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// Teed from an origin binary cache while serving a client.
+    Origin,
+    /// Uploaded directly by a client through PUT.
+    Client,
+    /// Written by the gateway to record that no origin has a narinfo.
+    NegativeMarker,
 }
 
-pub struct App {
-    client: reqwest::Client,
+impl Source {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Source::Origin => "origin",
+            Source::Client => "client",
+            Source::NegativeMarker => "negative-marker",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "origin" => Some(Source::Origin),
+            "client" => Some(Source::Client),
+            "negative-marker" => Some(Source::NegativeMarker),
+            _ => None,
+        }
+    }
+}
+
+/// Provenance recorded as S3 object metadata on upload.
+#[derive(Clone, Debug, Serialize)]
+pub struct Provenance {
+    pub source: Source,
+    pub origin_url: Option<String>,
+    pub fetched_at: DateTime<Utc>,
+}
+
+impl Provenance {
+    pub fn origin(url: impl Into<String>) -> Self {
+        Self {
+            source: Source::Origin,
+            origin_url: Some(url.into()),
+            fetched_at: Utc::now(),
+        }
+    }
+
+    pub fn client() -> Self {
+        Self {
+            source: Source::Client,
+            origin_url: None,
+            fetched_at: Utc::now(),
+        }
+    }
+
+    fn negative_marker() -> Self {
+        Self {
+            source: Source::NegativeMarker,
+            origin_url: None,
+            fetched_at: Utc::now(),
+        }
+    }
+
+    /// Adds the provenance metadata headers to an S3 `PUT`.
+    fn tag(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let req = req
+            .header("x-amz-meta-source", self.source.as_str())
+            .header(
+                "x-amz-meta-fetched-at",
+                self.fetched_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            );
+        match &self.origin_url {
+            Some(origin_url) => req.header("x-amz-meta-origin-url", origin_url),
+            None => req,
+        }
+    }
+}
+
+/// An upload to S3 that has started but not yet finished.
+#[derive(Clone, Serialize)]
+pub struct InFlight {
+    pub path: String,
+    pub source: Source,
+    pub size: Option<u64>,
+    pub started_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct InFlightUploads {
+    next_id: AtomicU64,
+    uploads: Mutex<HashMap<u64, InFlight>>,
+}
+
+impl InFlightUploads {
+    fn register(self: &Arc<Self>, upload: InFlight) -> InFlightGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.uploads.lock().unwrap().insert(id, upload);
+        InFlightGuard {
+            id,
+            uploads: self.clone(),
+        }
+    }
+}
+
+/// Removes its upload from the in-flight list when dropped.
+struct InFlightGuard {
+    id: u64,
+    uploads: Arc<InFlightUploads>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.uploads.uploads.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Everything derived from [`Config`] that is swapped on reload.
+struct Settings {
     mirrors: Vec<Mirror>,
     origins: Vec<Origin>,
     aws_endpoint: Url,
     aws_signer: AwsSigner,
-    cache: moka::future::Cache<String, CacheItem>,
+    presign_ttl: Duration,
+    negative_marker_ttl: Option<Duration>,
+    admin_token: Option<String>,
+    limits: Limits,
+    acl: Acl,
+    write: Write,
+    headers: Headers,
+    keys: KeyFilter,
+    cluster: Option<Cluster>,
 }
 
-impl App {
-    const TTL: Duration = Duration::from_secs(5 * 60);
-
-    pub fn from_config(config: Config) -> anyhow::Result<Self> {
-        let client = Client::builder().redirect(Policy::none()).build()?;
-
+impl Settings {
+    async fn new(config: Config) -> anyhow::Result<Self> {
         let aws_signer = AwsSigner::new(
             config.s3.access_key_id,
             config.s3.access_key_secret,
@@ -81,124 +355,552 @@ impl App {
             u
         };
 
-        let cache = moka::future::Cache::builder()
-            .time_to_live(Self::TTL)
-            .build();
-
         Ok(Self {
-            client,
             mirrors: config.mirrors,
             origins: config.origins,
             aws_signer,
             aws_endpoint,
+            presign_ttl: Duration::from_secs(config.cache.presign_ttl),
+            negative_marker_ttl: config.cache.negative_marker_ttl.map(Duration::from_secs),
+            admin_token: config.admin.map(|a| a.token),
+            limits: config.limits,
+            acl: config.acl,
+            write: config.write,
+            headers: config.headers,
+            keys: config.keys.compile()?,
+            cluster: match config.cluster {
+                Some(cluster) => Some(cluster.resolve().await?),
+                None => None,
+            },
+        })
+    }
+
+    fn object_url(&self, path: &str) -> Result<Url, Error> {
+        error::join(&self.aws_endpoint, path)
+    }
+
+    fn presign(&self, path: &str) -> Result<String, Error> {
+        let url = self.object_url(path)?;
+        Ok(self.aws_signer.sign_url(url, self.presign_ttl).to_string())
+    }
+}
+
+/// Sends `req`, giving up if no response headers arrive within `timeout`.
+async fn execute(
+    client: &Client,
+    req: reqwest::Request,
+    timeout: Duration,
+) -> Result<reqwest::Response, Error> {
+    let url = req.url().to_string();
+    tokio::time::timeout(timeout, client.execute(req))
+        .await
+        .map_err(|_| Error::Timeout {
+            url,
+            after: timeout,
+        })?
+        .map_err(Error::from)
+}
+
+/// The result label of an S3 `PUT`: `exists` if a conditional write found
+/// the object already stored by another writer.
+fn put_result(resp: reqwest::Response) -> reqwest::Result<&'static str> {
+    if resp.status() == reqwest::StatusCode::PRECONDITION_FAILED {
+        return Ok("exists");
+    }
+    resp.error_for_status().map(|_| "success")
+}
+
+/// Resolves the `location` of a redirect from `url` and checks it against
+/// the redirect policy of `origin`. `visited` holds the URLs fetched before
+/// `url`. Relative locations are resolved against `url`.
+fn redirect(
+    origin: &Origin,
+    visited: &[Url],
+    url: &Url,
+    location: &HeaderValue,
+) -> Result<Url, Error> {
+    let Ok(location) = location.to_str() else {
+        return Err(Error::bad_upstream(url, "non-UTF-8 redirect location"));
+    };
+    let next = url
+        .join(location)
+        .map_err(|_| Error::bad_upstream(url, format!("invalid redirect location {location:?}")))?;
+    if visited.len() >= origin.max_redirects {
+        let reason = format!("more than {} redirects", origin.max_redirects);
+        return Err(Error::bad_upstream(url, reason));
+    }
+    if !matches!(next.scheme(), "http" | "https") {
+        return Err(Error::bad_upstream(url, format!("redirect to {next}")));
+    }
+    if !origin.cross_host_redirects && next.host_str() != origin.url.host_str() {
+        let reason = format!("cross-host redirect to {next} not followed");
+        return Err(Error::bad_upstream(url, reason));
+    }
+    if next == *url || visited.contains(&next) {
+        return Err(Error::bad_upstream(&next, "redirect loop"));
+    }
+    Ok(next)
+}
+
+/// Whether a negative marker with these headers was written within `ttl` of
+/// `now`. Markers from up to `ttl` in the future, written by a replica whose
+/// clock is ahead, are fresh. Markers further ahead or without a valid
+/// timestamp are stale, so that a bad clock cannot pin a miss.
+fn is_fresh(headers: &HeaderMap, ttl: Duration, now: DateTime<Utc>) -> bool {
+    headers
+        .get("x-amz-meta-fetched-at")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+        .is_some_and(|t| {
+            (now - t.with_timezone(&Utc))
+                .abs()
+                .to_std()
+                .is_ok_and(|age| age < ttl)
+        })
+}
+
+/// Path of the S3 object recording that no origin has `path`. The key filter
+/// rejects keys starting with `_`, so markers cannot collide with them.
+fn negative_marker(path: &str) -> String {
+    format!("/_negative/{}", path.trim_start_matches('/'))
+}
+
+/// Outcome of probing a single upstream.
+#[derive(Clone, Serialize)]
+pub struct UpstreamStatus {
+    pub kind: &'static str,
+    pub url: String,
+    pub error: Option<String>,
+}
+
+/// Result of [`App::check_upstreams`], cached for [`HEALTH_TTL`].
+#[derive(Serialize)]
+pub struct Health {
+    pub checked_at: DateTime<Utc>,
+    pub upstreams: Vec<UpstreamStatus>,
+    #[serde(skip)]
+    expires_at: Instant,
+}
+
+impl Health {
+    /// S3 must be reachable with our credentials, and at least one origin
+    /// must be up to fill misses.
+    pub fn ready(&self) -> bool {
+        let ok = |kind| {
+            self.upstreams
+                .iter()
+                .filter(|u| u.kind == kind)
+                .any(|u| u.error.is_none())
+        };
+        ok("s3") && ok("origin")
+    }
+}
+
+const HEALTH_TTL: Duration = Duration::from_secs(10);
+
+/// Time allowed to connect to any upstream.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The URL an origin served a path from and its response, with the origin
+/// concurrency permit taken to probe for it unless the URL was cached.
+pub type OriginResponse = (String, reqwest::Response, Option<OwnedSemaphorePermit>);
+
+pub struct App {
+    client: reqwest::Client,
+    settings: ArcSwap<Settings>,
+    cache: LookupCache,
+    cache_config: Cache,
+    upload_queue: UploadQueue,
+    limiter: Limiter,
+    uploads: Arc<InFlightUploads>,
+    background: TaskTracker,
+    shutdown: CancellationToken,
+    health: tokio::sync::Mutex<Option<Arc<Health>>>,
+    peer_health: PeerHealth,
+}
+
+impl App {
+    pub async fn from_config(config: Config) -> anyhow::Result<Self> {
+        let client = Client::builder()
+            .redirect(Policy::none())
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()?;
+        let cache = LookupCache::new(
+            config.cache.ttls(),
+            config.cache.capacity,
+            config.cache.path.as_deref(),
+        )
+        .await?;
+        let cache_config = config.cache.clone();
+        let upload_queue = UploadQueue::new(config.upload.clone());
+        let limiter = Limiter::new(&config.limits);
+        let settings = Settings::new(config).await?;
+
+        Ok(Self {
+            client,
+            settings: ArcSwap::from_pointee(settings),
             cache,
+            cache_config,
+            upload_queue,
+            limiter,
+            uploads: Arc::default(),
+            background: TaskTracker::new(),
+            shutdown: CancellationToken::new(),
+            health: tokio::sync::Mutex::default(),
+            peer_health: PeerHealth::default(),
         })
     }
 
-    pub async fn get_mirror(&self, path: &str) -> Option<String> {
-        match self.cache.get(path).await {
-            Some(CacheItem::Mirror(s)) => return Some(s),
+    /// Atomically replaces the settings derived from `config`. Requests that
+    /// already started keep using the previous settings.
+    pub async fn reload(&self, config: Config) -> anyhow::Result<()> {
+        if config.cache.path != self.cache_config.path
+            || config.cache.capacity != self.cache_config.capacity
+        {
+            tracing::warn!("cache.path and cache.capacity changes require a restart");
+        }
+        if &config.upload != self.upload_queue.config() {
+            tracing::warn!("upload changes require a restart");
+        }
+        if config.limits.origin_concurrency != self.limiter.origin_concurrency() {
+            tracing::warn!("limits.origin_concurrency changes require a restart");
+        }
+        // Nothing is applied until the whole config has validated.
+        let ttls = config.cache.ttls();
+        let settings = Settings::new(config).await?;
+        self.settings.store(Arc::new(settings));
+        self.cache.set_ttls(ttls);
+        Ok(())
+    }
+
+    pub fn admin_token(&self) -> Option<String> {
+        self.settings.load().admin_token.clone()
+    }
+
+    pub fn write_config(&self) -> Write {
+        self.settings.load().write.clone()
+    }
+
+    /// Filters the headers of an origin response for the client.
+    pub fn proxied_headers(&self, upstream: &reqwest::header::HeaderMap) -> HeaderMap {
+        self.settings.load().headers.proxied(upstream)
+    }
+
+    /// The cluster replica that owns `path`, unless it is this one.
+    pub fn cluster_owner(&self, path: &str) -> Option<Url> {
+        let settings = self.settings.load();
+        settings.cluster.as_ref()?.owner(path).cloned()
+    }
+
+    /// Whether forwarded requests from `ip` come from a cluster peer.
+    pub fn is_cluster_peer(&self, ip: IpAddr) -> bool {
+        let settings = self.settings.load();
+        settings.cluster.as_ref().is_some_and(|c| c.is_peer(ip))
+    }
+
+    /// Whether forwarding to `peer` failed within `cluster.backoff`.
+    pub fn cluster_peer_down(&self, peer: &Url) -> bool {
+        self.peer_health.is_down(peer, Instant::now())
+    }
+
+    /// Sends a lookup on to the cluster replica that owns its path, on
+    /// behalf of `client`. A failure marks the owner down for `cluster.backoff`.
+    pub async fn forward(
+        &self,
+        owner: &Url,
+        method: &Method,
+        path: &str,
+        headers: &HeaderMap,
+        client: IpAddr,
+    ) -> Result<reqwest::Response, Error> {
+        let (timeout, backoff) = self
+            .settings
+            .load()
+            .cluster
+            .as_ref()
+            .map_or((Duration::ZERO, Duration::ZERO), |c| {
+                (c.timeout(), c.backoff())
+            });
+        let url = error::join(owner, path)?;
+        let mut req = self
+            .client
+            .request(method.clone(), url.clone())
+            .header(cluster::FORWARDED, "1")
+            .header("x-forwarded-for", client.to_string());
+        if let Some(authorization) = headers.get(reqwest::header::AUTHORIZATION) {
+            req = req.header(reqwest::header::AUTHORIZATION, authorization);
+        }
+        let mut req = req.build()?;
+        telemetry::inject(req.headers_mut());
+        let res = execute(&self.client, req, timeout).await;
+        match &res {
+            Ok(_) => self.peer_health.mark_up(owner),
+            Err(_) => self.peer_health.mark_down(owner, Instant::now() + backoff),
+        }
+        res
+    }
+
+    pub fn key_allowed(&self, path: &str) -> bool {
+        self.settings.load().keys.allows(path)
+    }
+
+    pub fn acl_allows(&self, method: &Method, ip: IpAddr) -> bool {
+        self.settings.load().acl.allows(method, ip)
+    }
+
+    pub fn rate_limit(
+        &self,
+        ip: Option<IpAddr>,
+        token: Option<[u8; 32]>,
+    ) -> Result<(), (&'static str, Duration)> {
+        self.limiter.check(&self.settings.load().limits, ip, token)
+    }
+
+    pub fn prune_rate_limits(&self) {
+        self.limiter.prune(&self.settings.load().limits);
+    }
+
+    /// Returns the URL to redirect to if a mirror or S3 has `path`.
+    #[tracing::instrument(skip(self), fields(winner))]
+    pub async fn get_mirror(&self, path: &str) -> Result<Option<(String, Decision)>, Error> {
+        let settings = self.settings.load_full();
+        // Every request starts here, so this is where cache hits are counted.
+        let cached = self.cache.get(path).await;
+        let result = if cached.is_some() { "hit" } else { "miss" };
+        counter!("nix_store_gateway_lookup_cache_requests", "result" => result).increment(1);
+        match cached {
+            Some(CacheItem::Mirror(s)) => return Ok(Some((s, Decision::Mirror))),
+            Some(CacheItem::Store) => return Ok(Some((settings.presign(path)?, Decision::S3))),
             Some(CacheItem::Origin(_) | CacheItem::NotExistOrigin | CacheItem::NotExistMirror) => {
-                return None;
+                return Ok(None);
             }
             None => {}
         }
 
-        let tasks = self
+        let mut probes = settings
             .mirrors
             .iter()
             .map(|mirror| {
-                let url = mirror.url.join(path.trim_start_matches('/')).unwrap();
-                let req = self.client.get(url.clone()).build().unwrap();
-                (req, url)
+                let url = error::join(&mirror.url, path)?;
+                let req = self.client.get(url.clone()).build()?;
+                Ok((req, CacheItem::Mirror(url.to_string())))
             })
-            .chain(Some({
-                let url = self
-                    .aws_endpoint
-                    .join(path.trim_start_matches('/'))
-                    .unwrap();
-                let sign = self
-                    .aws_signer
-                    .sign(self.client.head(url.clone()).build().unwrap());
-                let geturl = self.aws_signer.sign_url(url, Self::TTL * 5);
-                (sign, geturl)
-            }))
-            .rev()
-            .map(|(req, url)| {
-                Box::pin(async move {
-                    if let Ok(resp) = self.client.execute(req).await {
-                        let status = resp.status().as_u16();
-                        if (200..300).contains(&status) {
-                            return Ok(url.to_string());
-                        }
-                    }
-                    Err(())
-                })
-            });
+            .collect::<Result<Vec<_>, Error>>()?;
+        let url = settings.object_url(path)?;
+        probes.push((self.client.head(url).build()?, CacheItem::Store));
+
+        let tasks = probes.into_iter().rev().map(|(mut req, item)| {
+            let kind = match item {
+                CacheItem::Store => "s3",
+                _ => "mirror",
+            };
+            let host = req.url().host_str().unwrap_or_default().to_string();
+            let span = tracing::info_span!("probe", kind, host, found = tracing::field::Empty);
+            span.in_scope(|| telemetry::inject(req.headers_mut()));
+            if let CacheItem::Store = item {
+                req = settings.aws_signer.sign(req);
+            }
+            Box::pin(
+                async move {
+                    let start = Instant::now();
+                    let found = match self.client.execute(req).await {
+                        Ok(resp) => resp.status().is_success(),
+                        Err(_) => false,
+                    };
+                    tracing::Span::current().record("found", found);
+                    observe_probe(kind, host, start, found);
+                    if found { Ok(item) } else { Err(()) }
+                }
+                .instrument(span),
+            )
+        });
 
         let t = tokio::time::timeout(
             std::time::Duration::from_secs(2),
             futures::future::select_ok(tasks),
         );
         let v = t.await;
-        if let Ok(Ok((url, _))) = v {
-            self.cache
-                .insert(path.to_string(), CacheItem::Mirror(url.clone()))
-                .await;
-            Some(url)
+        if let Ok(Ok((item, _))) = v {
+            let winner = match &item {
+                CacheItem::Mirror(url) => url.as_str(),
+                _ => "s3",
+            };
+            tracing::Span::current().record("winner", winner);
+            self.cache.insert(path.to_string(), item.clone()).await;
+            match item {
+                CacheItem::Mirror(url) => Ok(Some((url, Decision::Mirror))),
+                _ => Ok(Some((settings.presign(path)?, Decision::S3))),
+            }
         } else {
             self.cache
                 .insert(path.to_string(), CacheItem::NotExistMirror)
                 .await;
-            None
+            Ok(None)
         }
     }
 
-    pub async fn get_origin(&self, path: &str) -> Option<(String, reqwest::Response)> {
-        match self.cache.get(path).await {
+    /// Fetches `path` from the first origin that has it. On a miss, the inner
+    /// error tells whether a cached negative result was used. Fails if no
+    /// origin could answer at all, or if origins must be probed while
+    /// `limits.origin_concurrency` fetches are running. A miss is only cached
+    /// if every origin answered.
+    #[tracing::instrument(skip(self), fields(winner))]
+    pub async fn get_origin(&self, path: &str) -> Result<Result<OriginResponse, Decision>, Error> {
+        let settings = self.settings.load_full();
+        let cached = match self.cache.get(path).await {
+            Some(CacheItem::Store) => Some(CacheItem::Mirror(settings.presign(path)?)),
+            v => v,
+        };
+        match cached {
             Some(CacheItem::Mirror(u) | CacheItem::Origin(u)) => {
-                let req = self.client.get(u.clone()).build().unwrap();
-                if let Ok(resp) = self.client.execute(req).await {
+                let mut req = self.client.get(u.clone()).build()?;
+                telemetry::inject(req.headers_mut());
+                let timeout = settings.origins.iter().map(Origin::timeout).max();
+                let timeout = timeout.unwrap_or(Duration::from_secs(default_origin_timeout()));
+                if let Ok(resp) = execute(&self.client, req, timeout).await {
                     let status = resp.status().as_u16();
                     if (200..300).contains(&status) {
-                        return Some((u, resp));
+                        return Ok(Ok((u, resp, None)));
                     }
                 }
             }
             Some(CacheItem::NotExistOrigin) => {
-                return None;
+                return Ok(Err(Decision::CachedNegative));
             }
-            Some(CacheItem::NotExistMirror) | None => {}
+            Some(CacheItem::NotExistMirror | CacheItem::Store) | None => {}
         }
 
-        let tasks = self.origins.iter().map(|origin| {
-            Box::pin(async move {
-                let mut url = origin.url.join(path.trim_start_matches('/')).unwrap();
-                loop {
-                    let req = self.client.get(url.clone()).build().unwrap();
-                    if let Ok(resp) = self.client.execute(req).await {
-                        match (resp.status().as_u16(), resp.headers().get("location")) {
-                            (200..=299, _) => return Ok((url.to_string(), resp)),
-                            (300..=399, Some(location)) => {
-                                url = location.to_str().unwrap().parse().unwrap();
-                            }
-                            _ => return Err(()),
-                        }
-                    }
+        let marker_ttl = settings
+            .negative_marker_ttl
+            .filter(|_| nix::is_narinfo(path));
+        if let Some(ttl) = marker_ttl {
+            match self.has_negative_marker(path, ttl).await {
+                Ok(true) => {
+                    self.cache
+                        .insert(path.to_string(), CacheItem::NotExistOrigin)
+                        .await;
+                    return Ok(Err(Decision::CachedNegative));
+                }
+                Ok(false) => {}
+                Err(err) => tracing::warn!("{} negative marker error: {}", path, err),
+            }
+        }
+
+        let urls = settings
+            .origins
+            .iter()
+            .map(|origin| Ok((origin, error::join(&origin.url, path)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let permit = self.limiter.origin_permit().ok_or(Error::OriginBusy)?;
+        let mut tasks = urls
+            .into_iter()
+            .map(|(origin, url)| {
+                let host = url.host_str().unwrap_or_default().to_string();
+                let span = tracing::info_span!(
+                    "probe",
+                    kind = "origin",
+                    host,
+                    found = tracing::field::Empty
+                );
+                async move {
+                    let start = Instant::now();
+                    let res = self.follow(origin, url).await;
+                    let found = matches!(res, Ok(Some(_)));
+                    tracing::Span::current().record("found", found);
+                    observe_probe("origin", host, start, found);
+                    res
                 }
+                .instrument(span)
             })
-        });
-        let v = futures::future::select_ok(tasks).await;
-        if let Ok(((url, resp), _)) = v {
-            self.cache
-                .insert(path.to_string(), CacheItem::Origin(url.clone()))
-                .await;
-            Some((url, resp))
-        } else {
-            self.cache
-                .insert(path.to_string(), CacheItem::NotExistOrigin)
-                .await;
-            None
+            .collect::<FuturesUnordered<_>>();
+
+        let mut answered = 0;
+        let mut failure = None;
+        while let Some(res) = tasks.next().await {
+            match res {
+                Ok(Some((url, resp))) => {
+                    tracing::Span::current().record("winner", url.as_str());
+                    self.cache
+                        .insert(path.to_string(), CacheItem::Origin(url.clone()))
+                        .await;
+                    return Ok(Ok((url, resp, Some(permit))));
+                }
+                Ok(None) => answered += 1,
+                Err(err) => failure = Some(err),
+            }
+        }
+        match failure {
+            Some(err) if answered == 0 => Err(err),
+            Some(err) => {
+                tracing::warn!("{} origin error: {}", path, err);
+                Ok(Err(Decision::PartialMiss))
+            }
+            None => {
+                self.cache
+                    .insert(path.to_string(), CacheItem::NotExistOrigin)
+                    .await;
+                if marker_ttl.is_some()
+                    && let Err(err) = self.put_negative_marker(path).await
+                {
+                    tracing::warn!("{} negative marker error: {}", path, err);
+                }
+                Ok(Err(Decision::Miss))
+            }
+        }
+    }
+
+    /// Whether the negative marker of `path` was written within `ttl`. Stale
+    /// markers are deleted as they are found.
+    async fn has_negative_marker(&self, path: &str, ttl: Duration) -> Result<bool, Error> {
+        let marker = negative_marker(path);
+        let Some(headers) = self.head_object(&marker).await? else {
+            return Ok(false);
+        };
+        if is_fresh(&headers, ttl, Utc::now()) {
+            return Ok(true);
+        }
+        self.delete(&marker).await?;
+        Ok(false)
+    }
+
+    async fn put_negative_marker(&self, path: &str) -> Result<(), Error> {
+        let settings = self.settings.load_full();
+        let url = settings.object_url(&negative_marker(path))?;
+        let req = Provenance::negative_marker()
+            .tag(self.client.put(url).header("content-length", 0))
+            .build()?;
+        let sign = settings.aws_signer.sign(req);
+        self.client.execute(sign).await?.error_for_status()?;
+        Ok(())
+    }
+
+    /// GETs `url` from `origin`, following redirects, and returns the final
+    /// URL with its response. Returns `None` if the origin does not have it.
+    async fn follow(
+        &self,
+        origin: &Origin,
+        mut url: Url,
+    ) -> Result<Option<(String, reqwest::Response)>, Error> {
+        let mut visited = Vec::new();
+        loop {
+            let mut req = self.client.get(url.clone()).build()?;
+            telemetry::inject(req.headers_mut());
+            let resp = execute(&self.client, req, origin.timeout()).await?;
+            match (
+                resp.status().as_u16(),
+                resp.headers().get(reqwest::header::LOCATION),
+            ) {
+                (200..=299, _) => return Ok(Some((url.to_string(), resp))),
+                (status @ 500..=599, _) => {
+                    return Err(Error::bad_upstream(&url, format!("status {status}")));
+                }
+                (300..=399, Some(location)) => {
+                    let next = redirect(origin, &visited, &url, location)?;
+                    visited.push(url);
+                    url = next;
+                }
+                _ => return Ok(None),
+            }
         }
     }
 
@@ -206,44 +908,659 @@ impl App {
         &self,
         path: &str,
         size: Option<u64>,
+        content_type: &str,
+        provenance: &Provenance,
         data: T,
-    ) -> impl Future<Output = anyhow::Result<()>> + use<E, T>
+    ) -> impl Future<Output = Result<(), Error>> + use<E, T>
     where
         E: Into<Box<dyn std::error::Error + Send + Sync>>,
         T: Stream<Item = Result<Bytes, E>> + Send + 'static,
     {
-        let url = self
-            .aws_endpoint
-            .join(path.trim_start_matches('/'))
-            .unwrap();
-        let mut req = self
-            .client
-            .put(url.clone())
-            .body(reqwest::Body::wrap_stream(data));
-        if let Some(size) = size {
-            req = req.header("content-length", size);
-        }
-        let sign = self.aws_signer.sign(req.build().unwrap());
+        let settings = self.settings.load_full();
+        let source = provenance.source;
+        let conditional = source == Source::Origin && self.upload_queue.config().conditional;
+        let data = data.map(move |chunk| {
+            if let Ok(chunk) = &chunk {
+                counter!("nix_store_gateway_bytes_uploaded", "source" => source.as_str())
+                    .increment(chunk.len() as u64);
+            }
+            chunk
+        });
+        let req = settings.object_url(path).and_then(|url| {
+            let mut req = self.client.put(url).body(reqwest::Body::wrap_stream(data));
+            if let Some(size) = size {
+                req = req.header("content-length", size);
+            }
+            req = provenance.tag(req.header("content-type", content_type));
+            if conditional {
+                req = req.header("if-none-match", "*");
+            }
+            Ok(req.build()?)
+        });
         let client = self.client.clone();
         let cache = self.cache.clone();
-        let geturl = self.aws_signer.sign_url(url, Self::TTL * 5);
         let p = path.to_string();
+        let guard = self.uploads.register(InFlight {
+            path: p.clone(),
+            source,
+            size,
+            started_at: Utc::now(),
+        });
+        let span = tracing::info_span!("s3_upload", path = p.as_str(), source = source.as_str());
         async move {
-            let _ = client.execute(sign).await?.error_for_status()?;
-            cache.insert(p, CacheItem::Mirror(geturl.to_string())).await;
+            let _guard = guard;
+            let start = Instant::now();
+            let sign = settings.aws_signer.sign(req?);
+            let res = client.execute(sign).await.and_then(put_result);
+            let result = *res.as_ref().unwrap_or(&"failure");
+            counter!("nix_store_gateway_upload", "source" => source.as_str(), "result" => result)
+                .increment(1);
+            histogram!(
+                "nix_store_gateway_upload_duration_seconds",
+                "source" => source.as_str(),
+                "result" => result,
+            )
+            .record(start.elapsed());
+            res?;
+            cache.insert(p, CacheItem::Store).await;
             Ok(())
         }
+        .instrument(span)
     }
 
-    pub async fn delete(&self, path: &str) -> anyhow::Result<()> {
-        let url = self
-            .aws_endpoint
-            .join(path.trim_start_matches('/'))
-            .unwrap();
-        let req = self.client.delete(url.clone());
-        let sign = self.aws_signer.sign(req.build().unwrap());
+    pub async fn delete(&self, path: &str) -> Result<(), Error> {
+        let settings = self.settings.load_full();
+        let url = settings.object_url(path)?;
+        let sign = settings.aws_signer.sign(self.client.delete(url).build()?);
         let _ = self.client.execute(sign).await?.error_for_status()?;
         self.cache.remove(path).await;
         Ok(())
     }
+
+    /// Returns the headers of an object stored in S3, or `None` if it does
+    /// not exist.
+    async fn head_object(&self, path: &str) -> Result<Option<HeaderMap>, Error> {
+        let settings = self.settings.load_full();
+        let url = settings.object_url(path)?;
+        let sign = settings.aws_signer.sign(self.client.head(url).build()?);
+        let resp = self.client.execute(sign).await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(resp.error_for_status()?.headers().clone()))
+    }
+
+    /// Returns the headers of `path` in our S3 store, ignoring mirrors and
+    /// origins.
+    pub async fn stat(&self, path: &str) -> Result<Option<HeaderMap>, Error> {
+        let headers = self.head_object(path).await?;
+        if headers.is_some() {
+            self.cache.insert(path.to_string(), CacheItem::Store).await;
+        }
+        Ok(headers)
+    }
+
+    /// Like [`App::stat`], but answers from the lookup cache when it can.
+    pub async fn exists(&self, path: &str) -> Result<bool, Error> {
+        if let Some(CacheItem::Store) = self.cache.get(path).await {
+            return Ok(true);
+        }
+        Ok(self.stat(path).await?.is_some())
+    }
+
+    /// How long clients may cache an answer based on `decision`.
+    pub fn max_age(&self, decision: Decision) -> Duration {
+        let ttls = self.cache.ttls();
+        match decision {
+            Decision::Mirror => ttls.mirror,
+            // Redirects to presigned URLs must expire well before the URLs.
+            Decision::S3 => ttls.mirror.min(self.settings.load().presign_ttl / 2),
+            Decision::Origin | Decision::Peer => ttls.origin,
+            Decision::Miss | Decision::CachedNegative => ttls.not_exist_origin,
+            Decision::PartialMiss => Duration::ZERO,
+        }
+    }
+
+    /// Reads the provenance metadata of an object stored in S3.
+    pub async fn provenance(&self, path: &str) -> Result<Option<Provenance>, Error> {
+        let Some(headers) = self.head_object(path).await? else {
+            return Ok(None);
+        };
+        let meta = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let Some(source) = meta("x-amz-meta-source").and_then(Source::parse) else {
+            return Ok(None);
+        };
+        Ok(Some(Provenance {
+            source,
+            origin_url: meta("x-amz-meta-origin-url").map(str::to_string),
+            fetched_at: meta("x-amz-meta-fetched-at")
+                .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+                .map_or_else(Utc::now, |v| v.with_timezone(&Utc)),
+        }))
+    }
+
+    pub async fn prune_cache(&self) {
+        self.cache.prune().await;
+    }
+
+    pub fn cache_entries(&self) -> u64 {
+        self.cache.entry_count()
+    }
+
+    pub async fn lookup(&self, path: &str) -> Option<CacheItem> {
+        self.cache.get(path).await
+    }
+
+    /// Forgets the lookup result for `path`, along with its negative marker
+    /// in S3.
+    pub async fn invalidate(&self, path: &str) -> Result<(), Error> {
+        self.cache.remove(path).await;
+        if self.settings.load().negative_marker_ttl.is_some() && nix::is_narinfo(path) {
+            self.delete(&negative_marker(path)).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn invalidate_prefix(&self, prefix: &str) -> usize {
+        self.cache.remove_prefix(prefix).await
+    }
+
+    pub fn invalidate_all(&self) {
+        self.cache.clear();
+    }
+
+    /// Drops any cached result for `path` and resolves it again.
+    pub async fn probe(&self, path: &str) -> Result<Option<CacheItem>, Error> {
+        self.invalidate(path).await?;
+        if self.get_mirror(path).await?.is_none() {
+            let _ = self.get_origin(path).await?;
+        }
+        Ok(self.cache.get(path).await)
+    }
+
+    /// Streams an origin response to the client while spooling it to disk,
+    /// then uploads the spooled body to S3. The client and the upload never
+    /// wait on each other, and the origin read continues after the client
+    /// disconnects within `upload.max_size` and `upload.orphan_timeout`.
+    /// Also returns whether the body is being spooled for upload. `permit`, if
+    /// any, is held until the origin read ends.
+    pub async fn tee(
+        self: &Arc<Self>,
+        path: &str,
+        origin_url: &str,
+        resp: reqwest::Response,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> (ReceiverStream<reqwest::Result<Bytes>>, bool) {
+        let config = self.upload_queue.config();
+        let max_size = config.max_size;
+        let orphan_timeout = Duration::from_secs(config.orphan_timeout);
+        let mut spool = if resp.content_length().is_some_and(|n| n > max_size) {
+            counter!("nix_store_gateway_upload_skipped", "reason" => "too_large").increment(1);
+            None
+        } else {
+            self.upload_queue.spool().await
+        };
+
+        let spooling = spool.is_some();
+        let content_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or(nix::content_type(path))
+            .to_string();
+        let (tx, rx) = mpsc::channel(64);
+        let app = self.clone();
+        let p = path.to_string();
+        let origin_url = origin_url.to_string();
+        self.spawn_background(path, async move {
+            let mut client = Some(tx);
+            let mut deadline = None;
+            let mut body = resp.bytes_stream();
+            let complete = loop {
+                let next = match deadline {
+                    Some(deadline) => {
+                        if let Ok(v) = tokio::time::timeout_at(deadline, body.next()).await {
+                            v
+                        } else {
+                            counter!("nix_store_gateway_upload_skipped", "reason" => "orphan_timeout")
+                                .increment(1);
+                            break false;
+                        }
+                    }
+                    None => body.next().await,
+                };
+                match next {
+                    None => break true,
+                    Some(Ok(buf)) => {
+                        if let Some(s) = &mut spool {
+                            if let Err(err) = s.write(&buf).await {
+                                tracing::error!("{} spool error: {:?}", p, err);
+                                counter!("nix_store_gateway_upload_skipped", "reason" => "spool_error")
+                                    .increment(1);
+                                spool = None;
+                            } else if s.len() > max_size {
+                                counter!("nix_store_gateway_upload_skipped", "reason" => "too_large")
+                                    .increment(1);
+                                spool = None;
+                            }
+                        }
+                        if let Some(tx) = &client {
+                            let len = buf.len() as u64;
+                            if tx.send(Ok(buf)).await.is_ok() {
+                                counter!("nix_store_gateway_bytes_served", "type" => "origin")
+                                    .increment(len);
+                            } else {
+                                client = None;
+                                deadline = Some(Instant::now() + orphan_timeout);
+                            }
+                        }
+                        if client.is_none() && spool.is_none() {
+                            break false;
+                        }
+                    }
+                    Some(Err(e)) => {
+                        tracing::warn!("{} origin read error: {:?}", p, e);
+                        if let Some(tx) = client {
+                            let _ = tx.send(Err(e)).await;
+                        }
+                        break false;
+                    }
+                }
+            };
+            drop(permit);
+            if complete && let Some(spool) = spool {
+                app.fill(&p, &origin_url, &content_type, spool).await;
+            }
+        });
+        (ReceiverStream::new(rx), spooling)
+    }
+
+    /// Uploads a spooled origin body, retrying with backoff.
+    async fn fill(&self, path: &str, origin_url: &str, content_type: &str, mut spool: Spool) {
+        let retries = self.upload_queue.config().retries;
+        for attempt in 0..=retries {
+            let res = async {
+                let _worker = self.upload_queue.worker().await;
+                if self.upload_queue.config().conditional && self.exists(path).await? {
+                    counter!("nix_store_gateway_upload_skipped", "reason" => "exists").increment(1);
+                    return anyhow::Ok(());
+                }
+                let size = spool.len();
+                let file = spool.reader().await?;
+                self.upload(
+                    path,
+                    Some(size),
+                    content_type,
+                    &Provenance::origin(origin_url),
+                    ReaderStream::new(file),
+                )
+                .await?;
+                anyhow::Ok(())
+            }
+            .await;
+            match res {
+                Ok(()) => return,
+                Err(err) => {
+                    tracing::warn!(
+                        "{} background upload attempt {} failed: {:?}",
+                        path,
+                        attempt + 1,
+                        err
+                    );
+                    counter!("nix_store_gateway_upload_attempt_failures").increment(1);
+                }
+            }
+            if attempt < retries {
+                tokio::time::sleep(self.upload_queue.backoff(attempt)).await;
+            }
+        }
+        tracing::error!(
+            "{} background upload failed after {} attempts",
+            path,
+            retries + 1
+        );
+        counter!("nix_store_gateway_upload_failures", "source" => "origin").increment(1);
+    }
+
+    /// Runs `task` in the background; [`App::drain`] waits for it on shutdown.
+    fn spawn_background<F>(&self, path: &str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        // The task outlives the request, so it gets its own trace linked to
+        // the request rather than a child span.
+        let span = tracing::info_span!(parent: None, "background_upload", path);
+        span.follows_from(tracing::Span::current());
+        let path = path.to_string();
+        let shutdown = self.shutdown.clone();
+        self.background.spawn(
+            async move {
+                tokio::select! {
+                    () = task => {}
+                    () = shutdown.cancelled() => {
+                        tracing::warn!("{} background upload abandoned", path);
+                    }
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Waits up to `timeout` for background uploads to finish, then aborts
+    /// the rest. Returns how many were abandoned.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        self.background.close();
+        if tokio::time::timeout(timeout, self.background.wait())
+            .await
+            .is_ok()
+        {
+            return 0;
+        }
+        let abandoned = self.background.len();
+        self.shutdown.cancel();
+        self.background.wait().await;
+        abandoned
+    }
+
+    pub fn uploads(&self) -> Vec<InFlight> {
+        self.uploads
+            .uploads
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Probes every configured upstream: `nix-cache-info` on mirrors and
+    /// origins, and a signed HEAD on the bucket for S3.
+    pub async fn check_upstreams(&self) -> Vec<UpstreamStatus> {
+        let settings = self.settings.load_full();
+        let get = |kind, base: &Url| {
+            let req = base
+                .join("nix-cache-info")
+                .map_err(anyhow::Error::from)
+                .and_then(|u| Ok(self.client.get(u).build()?));
+            (kind, req)
+        };
+        let targets = settings
+            .mirrors
+            .iter()
+            .map(|m| get("mirror", &m.url))
+            .chain(settings.origins.iter().map(|o| get("origin", &o.url)))
+            .chain(Some((
+                "s3",
+                self.client
+                    .head(settings.aws_endpoint.clone())
+                    .build()
+                    .map(|r| settings.aws_signer.sign(r))
+                    .map_err(anyhow::Error::from),
+            )));
+
+        let checks = targets.map(|(kind, req)| async move {
+            let url = req
+                .as_ref()
+                .map(|r| r.url().to_string())
+                .unwrap_or_default();
+            let result = match req {
+                Ok(req) => self.probe_upstream(req).await,
+                Err(err) => Err(err),
+            };
+            UpstreamStatus {
+                kind,
+                url,
+                error: result.err().map(|e| format!("{e:#}")),
+            }
+        });
+        futures::future::join_all(checks).await
+    }
+
+    /// Returns the last upstream check, running a new one if it is older
+    /// than [`HEALTH_TTL`]. Concurrent callers share a single check.
+    pub async fn health(&self) -> Arc<Health> {
+        let mut health = self.health.lock().await;
+        if let Some(h) = &*health
+            && h.expires_at > Instant::now()
+        {
+            return h.clone();
+        }
+        let h = Arc::new(Health {
+            upstreams: self.check_upstreams().await,
+            checked_at: Utc::now(),
+            expires_at: Instant::now() + HEALTH_TTL,
+        });
+        *health = Some(h.clone());
+        h
+    }
+
+    async fn probe_upstream(&self, req: reqwest::Request) -> anyhow::Result<()> {
+        let url = req.url();
+        let host = url.host_str().ok_or_else(|| anyhow!("missing host"))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| anyhow!("missing port"))?;
+        let _ = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| anyhow!("cannot resolve {host}: {e}"))?;
+        let resp = tokio::time::timeout(Duration::from_secs(5), self.client.execute(req))
+            .await
+            .map_err(|_| anyhow!("timed out"))??;
+        resp.error_for_status()?;
+        Ok(())
+    }
+}
+
+fn observe_probe(kind: &'static str, host: String, start: Instant, found: bool) {
+    histogram!(
+        "nix_store_gateway_upstream_probe_duration_seconds",
+        "kind" => kind,
+        "host" => host,
+        "result" => if found { "found" } else { "not_found" },
+    )
+    .record(start.elapsed());
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use quickcheck::{TestResult, quickcheck};
+
+    use super::*;
+
+    fn origin(cross_host_redirects: bool) -> Origin {
+        Origin {
+            url: Url::parse("https://origin.example/cache/").unwrap(),
+            max_redirects: 2,
+            cross_host_redirects,
+            timeout: default_origin_timeout(),
+        }
+    }
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    fn redirect_to(
+        origin: &Origin,
+        visited: &[Url],
+        from: &str,
+        location: &str,
+    ) -> Result<Url, Error> {
+        redirect(
+            origin,
+            visited,
+            &url(from),
+            &HeaderValue::from_str(location).unwrap(),
+        )
+    }
+
+    #[test]
+    fn relative_locations_resolve_against_the_request() {
+        let origin = origin(true);
+        let from = "https://origin.example/cache/a.narinfo";
+        let cases = [
+            ("b.narinfo", "https://origin.example/cache/b.narinfo"),
+            ("/other/b", "https://origin.example/other/b"),
+            ("../b", "https://origin.example/b"),
+            ("?v=2", "https://origin.example/cache/a.narinfo?v=2"),
+            ("//cdn.example/b", "https://cdn.example/b"),
+            ("http://cdn.example/b", "http://cdn.example/b"),
+        ];
+        for (location, next) in cases {
+            let res = redirect_to(&origin, &[], from, location);
+            assert_eq!(res.unwrap().as_str(), next, "{location}");
+        }
+    }
+
+    #[test]
+    fn unusable_locations_are_bad_upstreams() {
+        let origin = origin(true);
+        let from = url("https://origin.example/cache/a.narinfo");
+        let non_utf8 = HeaderValue::from_bytes(b"/b\xff").unwrap();
+        let err = redirect(&origin, &[], &from, &non_utf8).unwrap_err();
+        assert!(err.to_string().contains("non-UTF-8"), "{err}");
+        assert_eq!(err.status(), StatusCode::BAD_GATEWAY);
+
+        for location in [
+            "http://[::1",
+            "https://origin.example:99999/",
+            "ftp://origin.example/b",
+        ] {
+            let err = redirect_to(&origin, &[], from.as_str(), location).unwrap_err();
+            assert_eq!(err.status(), StatusCode::BAD_GATEWAY, "{location}");
+        }
+    }
+
+    #[test]
+    fn redirect_policy() {
+        let from = "https://origin.example/cache/a.narinfo";
+        let err = redirect_to(&origin(false), &[], from, "https://cdn.example/b").unwrap_err();
+        assert!(err.to_string().contains("cross-host"), "{err}");
+        assert!(redirect_to(&origin(false), &[], from, "/b").is_ok());
+
+        // A redirect to itself or to an earlier hop is a loop.
+        let err = redirect_to(&origin(true), &[], from, from).unwrap_err();
+        assert!(err.to_string().contains("loop"), "{err}");
+        let visited = [url("https://origin.example/b")];
+        let err = redirect_to(&origin(true), &visited, from, "/b").unwrap_err();
+        assert!(err.to_string().contains("loop"), "{err}");
+
+        let visited = [
+            url("https://origin.example/b"),
+            url("https://origin.example/c"),
+        ];
+        let err = redirect_to(&origin(true), &visited, from, "/d").unwrap_err();
+        assert!(err.to_string().contains("more than 2"), "{err}");
+    }
+
+    quickcheck! {
+        fn redirects_obey_policy(location: Vec<u8>, cross_host: bool) -> TestResult {
+            let Ok(location) = HeaderValue::from_bytes(&location) else {
+                return TestResult::discard();
+            };
+            let origin = origin(cross_host);
+            let from = url("https://origin.example/cache/a.narinfo");
+            let visited = [url("https://origin.example/cache/")];
+            TestResult::from_bool(redirect(&origin, &visited, &from, &location).map_or(true, |next| {
+                matches!(next.scheme(), "http" | "https")
+                    && next != from
+                    && !visited.contains(&next)
+                    && (cross_host || next.host_str() == Some("origin.example"))
+            }))
+        }
+    }
+
+    #[test]
+    fn negative_markers_expire() {
+        let now = Utc::now();
+        let ttl = Duration::from_hours(1);
+        let marker = |fetched_at: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-amz-meta-fetched-at", fetched_at.parse().unwrap());
+            headers
+        };
+        let at = |age: chrono::Duration| (now - age).to_rfc3339_opts(SecondsFormat::Secs, true);
+        assert!(is_fresh(
+            &marker(&at(chrono::Duration::minutes(59))),
+            ttl,
+            now
+        ));
+        assert!(!is_fresh(
+            &marker(&at(chrono::Duration::minutes(61))),
+            ttl,
+            now
+        ));
+        assert!(is_fresh(
+            &marker(&at(chrono::Duration::minutes(-5))),
+            ttl,
+            now
+        ));
+        assert!(!is_fresh(
+            &marker(&at(chrono::Duration::minutes(-61))),
+            ttl,
+            now
+        ));
+        assert!(!is_fresh(&marker("9999-12-31T23:59:59Z"), ttl, now));
+        assert!(!is_fresh(&marker("yesterday"), ttl, now));
+        assert!(!is_fresh(&HeaderMap::new(), ttl, now));
+    }
+
+    #[test]
+    fn provenance_tags_uploads() {
+        let client = Client::new();
+        let tagged = |provenance: Provenance| {
+            provenance
+                .tag(client.put("http://s3.example/b/x"))
+                .build()
+                .unwrap()
+                .headers()
+                .clone()
+        };
+        let headers = tagged(Provenance::origin("https://cache.nixos.org/x"));
+        assert_eq!(headers["x-amz-meta-source"], "origin");
+        assert_eq!(
+            headers["x-amz-meta-origin-url"],
+            "https://cache.nixos.org/x"
+        );
+        let headers = tagged(Provenance::negative_marker());
+        assert_eq!(headers["x-amz-meta-source"], "negative-marker");
+        assert!(!headers.contains_key("x-amz-meta-origin-url"));
+        let fetched_at = headers["x-amz-meta-fetched-at"].to_str().unwrap();
+        assert!(DateTime::parse_from_rfc3339(fetched_at).is_ok());
+
+        for source in [Source::Origin, Source::Client, Source::NegativeMarker] {
+            assert_eq!(Source::parse(source.as_str()), Some(source));
+        }
+    }
+
+    #[test]
+    fn conditional_write_conflicts_count_as_exists() {
+        let put = |status: u16| {
+            let resp = axum::http::Response::builder()
+                .status(status)
+                .body(String::new())
+                .unwrap();
+            put_result(reqwest::Response::from(resp))
+        };
+        assert_eq!(put(200).unwrap(), "success");
+        assert_eq!(put(412).unwrap(), "exists");
+        for status in [400, 403, 409, 501, 503] {
+            assert!(put(status).is_err(), "{status}");
+        }
+    }
+
+    #[tokio::test]
+    async fn silent_upstreams_time_out() {
+        let addr = error::tests::silent_upstream().await;
+        let client = Client::new();
+        let req = client
+            .get(format!("http://{addr}/a.narinfo"))
+            .build()
+            .unwrap();
+        let err = execute(&client, req, Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Timeout { .. }), "{err}");
+        assert_eq!(err.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
 }
@@ -0,0 +1,364 @@
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use arc_swap::ArcSwap;
+use moka::notification::RemovalCause;
+use redb::{Database, TableDefinition};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+const TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("lookup");
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum CacheItem {
+    Mirror(String),
+    /// Present in our S3 bucket; presigned URLs are issued on every read.
+    Store,
+    Origin(String),
+    NotExistMirror,
+    NotExistOrigin,
+}
+
+/// Lifetime of each kind of lookup result.
+#[derive(Clone, Copy)]
+pub struct Ttls {
+    pub mirror: Duration,
+    pub origin: Duration,
+    pub not_exist_mirror: Duration,
+    pub not_exist_origin: Duration,
+}
+
+impl Ttls {
+    fn of(&self, item: &CacheItem) -> Duration {
+        match item {
+            CacheItem::Mirror(_) | CacheItem::Store => self.mirror,
+            CacheItem::Origin(_) => self.origin,
+            CacheItem::NotExistMirror => self.not_exist_mirror,
+            CacheItem::NotExistOrigin => self.not_exist_origin,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    item: CacheItem,
+    /// Seconds since the unix epoch, so the TTL survives restarts.
+    expires_at: u64,
+}
+
+impl Entry {
+    fn remaining(&self) -> Duration {
+        let expires_at = UNIX_EPOCH + Duration::from_secs(self.expires_at);
+        expires_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+}
+
+struct EntryExpiry;
+
+impl moka::Expiry<String, Entry> for EntryExpiry {
+    fn expire_after_create(&self, _key: &String, value: &Entry, _now: Instant) -> Option<Duration> {
+        Some(value.remaining())
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &String,
+        value: &Entry,
+        _now: Instant,
+        _current: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(value.remaining())
+    }
+}
+
+enum Op {
+    Put(String, Vec<u8>),
+    Remove(String),
+    Clear,
+}
+
+/// In-memory lookup cache, optionally written through to an on-disk store so
+/// that lookups survive restarts.
+#[derive(Clone)]
+pub struct LookupCache {
+    entries: moka::future::Cache<String, Entry>,
+    store: Option<mpsc::UnboundedSender<Op>>,
+    ttls: Arc<ArcSwap<Ttls>>,
+}
+
+impl LookupCache {
+    pub async fn new(
+        ttls: Ttls,
+        capacity: Option<u64>,
+        path: Option<&Path>,
+    ) -> anyhow::Result<Self> {
+        let (store, loaded) = match path {
+            Some(path) => {
+                let db = Arc::new(Database::create(path)?);
+                let loaded = load(&db, capacity)?;
+                tracing::info!(
+                    "loaded {} lookup cache entries from {}",
+                    loaded.len(),
+                    path.display()
+                );
+                (Some(spawn_writer(db)), loaded)
+            }
+            None => (None, Vec::new()),
+        };
+
+        let mut builder = moka::future::Cache::builder().expire_after(EntryExpiry);
+        if let Some(capacity) = capacity {
+            builder = builder.max_capacity(capacity);
+        }
+        if let Some(store) = store.clone() {
+            // Explicit removals are written through by remove and clear.
+            builder = builder.eviction_listener(move |key: Arc<String>, _, cause| {
+                if matches!(cause, RemovalCause::Expired | RemovalCause::Size) {
+                    let _ = store.send(Op::Remove(key.to_string()));
+                }
+            });
+        }
+        let entries = builder.build();
+        for (key, entry) in loaded {
+            entries.insert(key, entry).await;
+        }
+
+        Ok(Self {
+            entries,
+            store,
+            ttls: Arc::new(ArcSwap::from_pointee(ttls)),
+        })
+    }
+
+    pub fn ttls(&self) -> Ttls {
+        **self.ttls.load()
+    }
+
+    pub fn set_ttls(&self, ttls: Ttls) {
+        self.ttls.store(Arc::new(ttls));
+    }
+
+    #[tracing::instrument(name = "cache_lookup", skip(self), fields(hit))]
+    pub async fn get(&self, key: &str) -> Option<CacheItem> {
+        let item = self.entries.get(key).await.map(|e| e.item);
+        tracing::Span::current().record("hit", item.is_some());
+        item
+    }
+
+    /// Evicts expired entries, which also removes them from disk.
+    pub async fn prune(&self) {
+        self.entries.run_pending_tasks().await;
+    }
+
+    pub fn entry_count(&self) -> u64 {
+        self.entries.entry_count()
+    }
+
+    pub async fn insert(&self, key: String, item: CacheItem) {
+        let expires_at = SystemTime::now() + self.ttls.load().of(&item);
+        let entry = Entry {
+            item,
+            expires_at: expires_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        if let Some(store) = &self.store
+            && let Ok(value) = serde_json::to_vec(&entry)
+        {
+            let _ = store.send(Op::Put(key.clone(), value));
+        }
+        self.entries.insert(key, entry).await;
+    }
+
+    pub async fn remove(&self, key: &str) {
+        if let Some(store) = &self.store {
+            let _ = store.send(Op::Remove(key.to_string()));
+        }
+        self.entries.remove(key).await;
+    }
+
+    /// Removes every entry whose key starts with `prefix`, returning how many
+    /// were removed.
+    pub async fn remove_prefix(&self, prefix: &str) -> usize {
+        let keys = self
+            .entries
+            .iter()
+            .filter(|(k, _)| k.starts_with(prefix))
+            .map(|(k, _)| k.to_string())
+            .collect::<Vec<_>>();
+        for key in &keys {
+            self.remove(key).await;
+        }
+        keys.len()
+    }
+
+    pub fn clear(&self) {
+        if let Some(store) = &self.store {
+            let _ = store.send(Op::Clear);
+        }
+        self.entries.invalidate_all();
+    }
+}
+
+/// Returns unexpired entries, at most `capacity` of them, and prunes the rest
+/// from disk. The entries that expire last are kept.
+fn load(db: &Database, capacity: Option<u64>) -> anyhow::Result<Vec<(String, Entry)>> {
+    let txn = db.begin_write()?;
+    let mut loaded = Vec::new();
+    {
+        let mut table = txn.open_table(TABLE)?;
+        table.retain(|key, value| {
+            let Ok(entry) = serde_json::from_slice::<Entry>(value) else {
+                return false;
+            };
+            if entry.remaining().is_zero() {
+                return false;
+            }
+            loaded.push((key.to_string(), entry));
+            true
+        })?;
+        let capacity = capacity.map_or(usize::MAX, |c| usize::try_from(c).unwrap_or(usize::MAX));
+        if loaded.len() > capacity {
+            loaded.sort_unstable_by_key(|(_, entry)| std::cmp::Reverse(entry.expires_at));
+            for (key, _) in loaded.drain(capacity..) {
+                table.remove(key.as_str())?;
+            }
+        }
+    }
+    txn.commit()?;
+    Ok(loaded)
+}
+
+/// Batches write-through operations into a single transaction per wakeup.
+fn spawn_writer(db: Arc<Database>) -> mpsc::UnboundedSender<Op> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut ops = Vec::new();
+        while rx.recv_many(&mut ops, 1024).await > 0 {
+            let batch = std::mem::take(&mut ops);
+            let db = db.clone();
+            let res = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+                let txn = db.begin_write()?;
+                {
+                    let mut table = txn.open_table(TABLE)?;
+                    for op in batch {
+                        match op {
+                            Op::Put(key, value) => {
+                                table.insert(key.as_str(), value.as_slice())?;
+                            }
+                            Op::Remove(key) => {
+                                table.remove(key.as_str())?;
+                            }
+                            Op::Clear => {
+                                table.retain(|_, _| false)?;
+                            }
+                        }
+                    }
+                }
+                txn.commit()?;
+                Ok(())
+            })
+            .await;
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => tracing::error!("lookup cache write error: {:?}", err),
+                Err(err) => tracing::error!("lookup cache writer panicked: {:?}", err),
+            }
+        }
+    });
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use redb::{ReadableDatabase, ReadableTable};
+
+    use super::*;
+
+    const TTLS: Ttls = Ttls {
+        mirror: Duration::from_mins(1),
+        origin: Duration::from_mins(1),
+        not_exist_mirror: Duration::from_mins(1),
+        not_exist_origin: Duration::from_mins(1),
+    };
+
+    fn rows(db: &Database) -> Vec<String> {
+        let txn = db.begin_read().unwrap();
+        let table = txn.open_table(TABLE).unwrap();
+        table
+            .iter()
+            .unwrap()
+            .map(|row| row.unwrap().0.value().to_string())
+            .collect()
+    }
+
+    /// Opens the database once the cache writer has let go of it.
+    async fn reopen(path: &Path) -> Database {
+        for _ in 0..200 {
+            if let Ok(db) = Database::create(path) {
+                return db;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("lookup cache database still open");
+    }
+
+    #[test]
+    fn load_keeps_latest_entries_up_to_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::create(dir.path().join("cache.redb")).unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let txn = db.begin_write().unwrap();
+        {
+            let mut table = txn.open_table(TABLE).unwrap();
+            for (key, expires_at) in [
+                ("/a", now + 10),
+                ("/b", now + 30),
+                ("/c", now + 20),
+                ("/d", now - 1),
+            ] {
+                let entry = Entry {
+                    item: CacheItem::Store,
+                    expires_at,
+                };
+                let value = serde_json::to_vec(&entry).unwrap();
+                table.insert(key, value.as_slice()).unwrap();
+            }
+        }
+        txn.commit().unwrap();
+
+        let mut loaded = load(&db, Some(2))
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        loaded.sort();
+        assert_eq!(loaded, ["/b", "/c"]);
+        assert_eq!(rows(&db), ["/b", "/c"]);
+    }
+
+    #[tokio::test]
+    async fn evicted_entries_are_removed_from_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.redb");
+        let cache = LookupCache::new(TTLS, Some(1), Some(&path)).await.unwrap();
+        for key in ["/a", "/b", "/c"] {
+            cache.insert(key.to_string(), CacheItem::Store).await;
+            cache.prune().await;
+        }
+        assert_eq!(cache.entry_count(), 1);
+        drop(cache);
+
+        let db = reopen(&path).await;
+        assert_eq!(rows(&db).len(), 1);
+    }
+}
@@ -0,0 +1,119 @@
+use std::path::PathBuf;
+
+use anyhow::bail;
+use clap::{Args, Parser, Subcommand};
+use reqwest::{Method, Url};
+
+#[derive(Parser)]
+#[command(version, about = "Gateway for layered Nix binary cache backends")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the gateway.
+    Serve {
+        /// Address to listen on.
+        #[arg(
+            long,
+            env = "NIX_STORE_GATEWAY_LISTEN",
+            default_value = "127.0.0.1:3000"
+        )]
+        listen: String,
+        /// Path to config.toml.
+        #[arg(long, env = "NIX_STORE_GATEWAY_CONFIG")]
+        config: PathBuf,
+        /// Seconds to wait for in-flight responses and background uploads on
+        /// shutdown before abandoning them.
+        #[arg(long, env = "NIX_STORE_GATEWAY_SHUTDOWN_TIMEOUT", default_value_t = 30)]
+        shutdown_timeout: u64,
+    },
+    /// Validate the configuration and probe every upstream, exiting non-zero
+    /// if anything is unreachable.
+    CheckConfig {
+        /// Path to config.toml.
+        #[arg(long, env = "NIX_STORE_GATEWAY_CONFIG")]
+        config: PathBuf,
+    },
+    /// Talk to the admin API of a running instance.
+    Admin {
+        #[command(flatten)]
+        remote: Remote,
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+}
+
+#[derive(Args)]
+pub struct Remote {
+    /// Base URL of the running gateway.
+    #[arg(
+        long,
+        env = "NIX_STORE_GATEWAY_URL",
+        default_value = "http://127.0.0.1:3000"
+    )]
+    url: Url,
+    /// Admin bearer token.
+    #[arg(long, env = "NIX_STORE_GATEWAY_ADMIN_TOKEN", hide_env_values = true)]
+    token: String,
+}
+
+#[derive(Subcommand)]
+pub enum AdminCommand {
+    /// Show the cached lookup result and provenance of a path.
+    Inspect { key: String },
+    /// Invalidate one path, every path under a prefix, or the whole cache.
+    Invalidate {
+        #[arg(conflicts_with_all = ["prefix", "all"], required_unless_present_any = ["prefix", "all"])]
+        key: Option<String>,
+        #[arg(long, conflicts_with = "all")]
+        prefix: Option<String>,
+        #[arg(long)]
+        all: bool,
+    },
+    /// Drop the cached result of a path and probe upstreams again.
+    Probe { key: String },
+    /// List in-flight uploads.
+    Uploads,
+}
+
+impl AdminCommand {
+    pub async fn run(self, remote: Remote) -> anyhow::Result<()> {
+        let key = |k: &str| format!("_admin/cache/{}", k.trim_start_matches('/'));
+        let mut prefix = None;
+        let (method, path) = match self {
+            AdminCommand::Inspect { key: k } => (Method::GET, key(&k)),
+            AdminCommand::Invalidate { key: Some(k), .. } => (Method::DELETE, key(&k)),
+            AdminCommand::Invalidate { prefix: p, .. } => {
+                prefix = p;
+                (Method::DELETE, "_admin/cache".to_string())
+            }
+            AdminCommand::Probe { key: k } => (
+                Method::POST,
+                format!("_admin/probe/{}", k.trim_start_matches('/')),
+            ),
+            AdminCommand::Uploads => (Method::GET, "_admin/uploads".to_string()),
+        };
+        let mut url = remote.url.join(&path)?;
+        if let Some(prefix) = prefix {
+            url.query_pairs_mut().append_pair("prefix", &prefix);
+        }
+
+        let resp = reqwest::Client::new()
+            .request(method, url)
+            .bearer_auth(remote.token)
+            .send()
+            .await?;
+        let status = resp.status();
+        let body = resp.text().await?;
+        if !status.is_success() {
+            bail!("{status}: {body}");
+        }
+        if !body.is_empty() {
+            println!("{body}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,338 @@
+use std::{collections::HashMap, io, net::IpAddr, sync::Mutex, time::Duration};
+
+use anyhow::bail;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::Method,
+    middleware::Next,
+    response::Response,
+};
+use metrics::counter;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::time::Instant;
+use url::{Host, Url};
+
+use crate::AppState;
+use crate::access_log::{Decision, Outcome};
+use crate::headers;
+use crate::proxy::{ClientIp, PeerAddr};
+
+/// Set on requests forwarded to a peer, which then serves them itself.
+pub const FORWARDED: &str = "x-nix-store-gateway-forwarded";
+
+#[derive(Deserialize, Clone)]
+pub struct Cluster {
+    /// URL the other replicas reach this one at, as listed in `peers`.
+    url: Url,
+    /// Every replica of the cluster. All replicas must list the same peers.
+    peers: Vec<Url>,
+    /// Seconds to wait for the owner's response headers before serving a
+    /// request locally.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    /// Seconds to serve a peer's paths locally after forwarding to it failed.
+    #[serde(default = "default_backoff")]
+    backoff: u64,
+    /// Addresses of `peers`, resolved when the config is loaded.
+    #[serde(skip)]
+    peer_ips: Vec<IpAddr>,
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+fn default_backoff() -> u64 {
+    30
+}
+
+impl Cluster {
+    /// Checks that this replica is one of `peers` and resolves the addresses
+    /// that forwarded requests are accepted from. Peers that do not resolve
+    /// yet are skipped until the next reload.
+    pub async fn resolve(mut self) -> anyhow::Result<Self> {
+        if !self.peers.contains(&self.url) {
+            bail!("cluster.url {} is not listed in cluster.peers", self.url);
+        }
+        self.peer_ips = Vec::new();
+        for peer in &self.peers {
+            match addresses(peer).await {
+                Ok(ips) => self
+                    .peer_ips
+                    .extend(ips.into_iter().map(|ip| ip.to_canonical())),
+                Err(err) => tracing::warn!("cluster peer {} does not resolve: {}", peer, err),
+            }
+        }
+        Ok(self)
+    }
+
+    /// The replica that resolves and uploads `path`, or `None` if it is this
+    /// one. Rendezvous hashing moves only the paths of a replica that joins
+    /// or leaves.
+    pub fn owner(&self, path: &str) -> Option<&Url> {
+        let score = |peer: &Url| {
+            Sha256::new()
+                .chain_update(peer.as_str())
+                .chain_update(path)
+                .finalize()
+        };
+        let owner = self.peers.iter().max_by_key(|peer| score(peer))?;
+        (*owner != self.url).then_some(owner)
+    }
+
+    /// Whether `ip` is the address of a peer.
+    pub fn is_peer(&self, ip: IpAddr) -> bool {
+        self.peer_ips.contains(&ip.to_canonical())
+    }
+
+    pub fn backoff(&self) -> Duration {
+        Duration::from_secs(self.backoff)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+/// Looks up the addresses of `url`'s host without blocking the runtime.
+async fn addresses(url: &Url) -> io::Result<Vec<IpAddr>> {
+    match url.host() {
+        Some(Host::Domain(domain)) => Ok(tokio::net::lookup_host((domain, 0))
+            .await?
+            .map(|addr| addr.ip())
+            .collect()),
+        Some(Host::Ipv4(ip)) => Ok(vec![ip.into()]),
+        Some(Host::Ipv6(ip)) => Ok(vec![ip.into()]),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, "no host")),
+    }
+}
+
+/// Peers that a forward recently failed for, and until when they are skipped.
+#[derive(Default)]
+pub struct PeerHealth {
+    down: Mutex<HashMap<Url, Instant>>,
+}
+
+impl PeerHealth {
+    pub fn is_down(&self, peer: &Url, now: Instant) -> bool {
+        let mut down = self.down.lock().unwrap();
+        match down.get(peer) {
+            Some(until) if *until > now => true,
+            Some(_) => {
+                down.remove(peer);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn mark_down(&self, peer: &Url, until: Instant) {
+        self.down.lock().unwrap().insert(peer.clone(), until);
+    }
+
+    pub fn mark_up(&self, peer: &Url) {
+        self.down.lock().unwrap().remove(peer);
+    }
+}
+
+/// Forwards lookups to the replica that owns the path, so that each path is
+/// resolved and uploaded once across the cluster. Requests are served locally
+/// if the owner cannot be reached, and for `cluster.backoff` after that.
+pub async fn forward(
+    State(app): State<AppState>,
+    ClientIp(client): ClientIp,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return next.run(request).await;
+    }
+    // Only peers may ask for a request to be served here regardless of owner.
+    if request.headers().contains_key(FORWARDED) {
+        let peer = request
+            .extensions()
+            .get::<ConnectInfo<PeerAddr>>()
+            .map(|ConnectInfo(PeerAddr(addr))| addr.ip());
+        if peer.is_some_and(|ip| app.is_cluster_peer(ip)) {
+            return next.run(request).await;
+        }
+        counter!("nix_store_gateway_cluster_forwarded", "result" => "untrusted").increment(1);
+    }
+    let Some(owner) = app.cluster_owner(request.uri().path()) else {
+        return next.run(request).await;
+    };
+    if app.cluster_peer_down(&owner) {
+        counter!("nix_store_gateway_cluster_forwarded", "result" => "skipped").increment(1);
+        return next.run(request).await;
+    }
+    let forwarded = app
+        .forward(
+            &owner,
+            request.method(),
+            request.uri().path(),
+            request.headers(),
+            client,
+        )
+        .await;
+    match forwarded {
+        Ok(resp) => {
+            counter!("nix_store_gateway_cluster_forwarded", "result" => "forwarded").increment(1);
+            relay(request.method(), resp, &owner)
+        }
+        Err(err) => {
+            counter!("nix_store_gateway_cluster_forwarded", "result" => "fallback").increment(1);
+            tracing::warn!(
+                "{} forward to {} failed: {}",
+                request.uri().path(),
+                owner,
+                err
+            );
+            next.run(request).await
+        }
+    }
+}
+
+fn relay(method: &Method, resp: reqwest::Response, owner: &Url) -> Response {
+    let len = if method == Method::HEAD {
+        None
+    } else {
+        resp.content_length()
+    };
+    let status = resp.status();
+    let relayed = headers::relayed(resp.headers());
+    let body = Body::from_stream(resp.bytes_stream());
+    let mut r = Response::new(headers::body(body, len));
+    *r.status_mut() = status;
+    *r.headers_mut() = relayed;
+    r.extensions_mut().insert(Outcome {
+        decision: Some(Decision::Peer),
+        upstream: owner.host_str().map(str::to_string),
+        upload: false,
+    });
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(n: usize) -> Vec<Url> {
+        (1..=n)
+            .map(|i| Url::parse(&format!("http://10.0.0.{i}:3000")).unwrap())
+            .collect()
+    }
+
+    fn cluster(url: &Url, peers: &[Url]) -> Cluster {
+        Cluster {
+            url: url.clone(),
+            peers: peers.to_vec(),
+            timeout: default_timeout(),
+            backoff: default_backoff(),
+            peer_ips: Vec::new(),
+        }
+    }
+
+    /// The owner of `path` as seen by `cluster`, including itself.
+    fn owner(cluster: &Cluster, path: &str) -> Url {
+        cluster.owner(path).unwrap_or(&cluster.url).clone()
+    }
+
+    fn paths() -> impl Iterator<Item = String> {
+        (0..2000).map(|i| format!("/{i:032}.narinfo"))
+    }
+
+    #[test]
+    fn every_replica_and_ordering_agrees_on_owner() {
+        let peers = peers(4);
+        let mut orderings = Vec::new();
+        for rotation in 0..peers.len() {
+            let mut ordering = peers.clone();
+            ordering.rotate_left(rotation);
+            orderings.push(ordering.clone());
+            ordering.reverse();
+            orderings.push(ordering);
+        }
+        let clusters = orderings
+            .iter()
+            .flat_map(|ordering| peers.iter().map(|url| cluster(url, ordering)))
+            .collect::<Vec<_>>();
+        for path in paths() {
+            let expected = owner(&clusters[0], &path);
+            for cluster in &clusters {
+                assert_eq!(owner(cluster, &path), expected, "{path}");
+            }
+        }
+    }
+
+    #[test]
+    fn only_this_replica_owns_what_it_serves() {
+        let peers = peers(3);
+        let cluster = cluster(&peers[1], &peers);
+        let local = paths().filter(|p| cluster.owner(p).is_none()).count();
+        // Roughly a third of the paths, and never this replica as a peer.
+        assert!((500..834).contains(&local), "{local}");
+        assert!(paths().all(|p| cluster.owner(&p) != Some(&peers[1])));
+    }
+
+    #[test]
+    fn removing_a_peer_moves_only_its_paths() {
+        let peers = peers(5);
+        let removed = &peers[2];
+        let before = cluster(&peers[0], &peers);
+        let remaining = peers
+            .iter()
+            .filter(|p| *p != removed)
+            .cloned()
+            .collect::<Vec<_>>();
+        let after = cluster(&peers[0], &remaining);
+        let mut moved = HashMap::<Url, usize>::new();
+        for path in paths() {
+            let old = owner(&before, &path);
+            let new = owner(&after, &path);
+            if old == *removed {
+                *moved.entry(new).or_default() += 1;
+            } else {
+                assert_eq!(old, new, "{path}");
+            }
+        }
+        // The removed replica's paths are spread over all the others.
+        assert_eq!(moved.len(), remaining.len());
+    }
+
+    #[tokio::test]
+    async fn url_must_be_a_peer() {
+        let peers = peers(3);
+        let outsider = Url::parse("http://10.0.0.9:3000").unwrap();
+        assert!(cluster(&outsider, &peers).resolve().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn only_peer_addresses_are_peers() {
+        let mut peers = peers(2);
+        peers.push(Url::parse("http://[fd00::1]:3000").unwrap());
+        peers.push(Url::parse("http://localhost:3000").unwrap());
+        let cluster = cluster(&peers[0], &peers).resolve().await.unwrap();
+        assert!(cluster.is_peer("10.0.0.1".parse().unwrap()));
+        assert!(cluster.is_peer("::ffff:10.0.0.2".parse().unwrap()));
+        assert!(cluster.is_peer("fd00::1".parse().unwrap()));
+        assert!(cluster.is_peer("127.0.0.1".parse().unwrap()));
+        assert!(!cluster.is_peer("10.0.0.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn failed_peers_are_skipped_for_the_backoff() {
+        let health = PeerHealth::default();
+        let peer = &peers(1)[0];
+        let now = Instant::now();
+        assert!(!health.is_down(peer, now));
+        health.mark_down(peer, now + Duration::from_secs(30));
+        assert!(health.is_down(peer, now + Duration::from_secs(29)));
+        assert!(!health.is_down(peer, now + Duration::from_secs(30)));
+
+        health.mark_down(peer, now + Duration::from_secs(30));
+        health.mark_up(peer);
+        assert!(!health.is_down(peer, now));
+    }
+}
@@ -1,24 +1,240 @@
+use std::time::Duration;
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use metrics::counter;
+use url::Url;
+
+use crate::limit;
+
+/// Why a request could not be served.
 #[derive(Debug)]
-pub struct Error {
-    pub details: String,
+pub enum Error {
+    /// The request path does not name an object below an upstream URL.
+    BadPath(String),
+    /// A request header the gateway relies on is malformed.
+    BadHeader(&'static str),
+    /// An upstream answered with something the gateway cannot use.
+    BadUpstream { url: String, reason: String },
+    /// An upstream sent no response headers in time.
+    Timeout { url: String, after: Duration },
+    /// An upstream could not be reached or returned an error status.
+    Upstream(reqwest::Error),
+    /// `limits.origin_concurrency` origin fetches are already running.
+    OriginBusy,
+}
+
+impl Error {
+    pub fn bad_upstream(url: &Url, reason: impl Into<String>) -> Self {
+        Error::BadUpstream {
+            url: url.to_string(),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::BadPath(_) | Error::BadHeader(_) => StatusCode::BAD_REQUEST,
+            Error::OriginBusy => StatusCode::TOO_MANY_REQUESTS,
+            Error::Timeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            Error::Upstream(err) if err.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            Error::BadUpstream { .. } | Error::Upstream(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.details)
+        match self {
+            Error::BadPath(path) => write!(f, "invalid path {path:?}"),
+            Error::BadHeader(name) => write!(f, "invalid {name} header"),
+            Error::BadUpstream { url, reason } => write!(f, "{url}: {reason}"),
+            Error::Timeout { url, after } => {
+                write!(f, "{url}: no response within {}s", after.as_secs_f64())
+            }
+            Error::Upstream(err) => write!(f, "{err}"),
+            Error::OriginBusy => write!(f, "too many origin fetches"),
+        }
     }
 }
 
-impl Error {
-    pub fn new(msg: impl Into<String>) -> Error {
-        Error {
-            details: msg.into(),
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Upstream(err) => Some(err),
+            _ => None,
         }
     }
 }
 
-impl std::error::Error for Error {
-    fn description(&self) -> &str {
-        &self.details
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Upstream(err)
+    }
+}
+
+/// Client errors are explained in the body; upstream details are only logged.
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        if let Error::OriginBusy = self {
+            counter!("nix_store_gateway_rate_limited", "scope" => "origin").increment(1);
+            return limit::too_many_requests(Duration::from_secs(1));
+        }
+        let status = self.status();
+        if status.is_client_error() {
+            return (status, format!("{self}\n")).into_response();
+        }
+        tracing::error!("{}", self);
+        status.into_response()
+    }
+}
+
+/// Resolves a request path against an upstream base URL. Paths that would
+/// leave the base, such as `http://…` or `../`, are rejected.
+pub fn join(base: &Url, path: &str) -> Result<Url, Error> {
+    let bad = || Error::BadPath(path.to_string());
+    let dir = base.join(".").map_err(|_| bad())?;
+    let url = base.join(path.trim_start_matches('/')).map_err(|_| bad())?;
+    if !url.as_str().starts_with(dir.as_str()) {
+        return Err(bad());
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::SocketAddr;
+
+    use quickcheck::quickcheck;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn base() -> Url {
+        Url::parse("https://cache.example/prefix/").unwrap()
+    }
+
+    #[test]
+    fn join_accepts_paths_below_base() {
+        let cases = [
+            ("/abc.narinfo", "https://cache.example/prefix/abc.narinfo"),
+            ("abc.narinfo", "https://cache.example/prefix/abc.narinfo"),
+            (
+                "/nar/abc.nar.xz",
+                "https://cache.example/prefix/nar/abc.nar.xz",
+            ),
+            ("//abc.narinfo", "https://cache.example/prefix/abc.narinfo"),
+            ("/a/../b", "https://cache.example/prefix/b"),
+            ("/a b", "https://cache.example/prefix/a%20b"),
+        ];
+        for (path, url) in cases {
+            assert_eq!(join(&base(), path).unwrap().as_str(), url, "{path}");
+        }
+        // Without a trailing slash, the last segment of the base is replaced.
+        let base = Url::parse("https://cache.example/prefix").unwrap();
+        assert_eq!(
+            join(&base, "/abc.narinfo").unwrap().as_str(),
+            "https://cache.example/abc.narinfo"
+        );
+    }
+
+    #[test]
+    fn join_rejects_paths_leaving_base() {
+        for path in [
+            "/http://evil.example/x",
+            "/mailto:x@evil.example",
+            "/../x",
+            "/a/../../x",
+            "/%2e%2e/x",
+            "/.%2E/x",
+            "/\\evil.example/x",
+            "/\\\\evil.example/x",
+            "/..\\x",
+        ] {
+            let err = join(&base(), path).unwrap_err();
+            assert!(matches!(err, Error::BadPath(ref p) if p == path), "{path}");
+            assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    quickcheck! {
+        fn join_never_leaves_base(path: String) -> bool {
+            join(&base(), &path).map_or(true, |url| url.as_str().starts_with(base().as_str()))
+        }
+
+        fn join_keeps_plain_keys(segments: Vec<String>) -> bool {
+            let segments = segments
+                .iter()
+                .map(|s| s.chars().filter(char::is_ascii_alphanumeric).collect::<String>())
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>();
+            let path = format!("/{}", segments.join("/"));
+            join(&base(), &path).is_ok_and(|url| url.path() == format!("/prefix{path}"))
+        }
+    }
+
+    #[test]
+    fn status_mapping() {
+        let url = base();
+        let builder = reqwest::Client::new()
+            .get("http://[::1")
+            .build()
+            .unwrap_err();
+        let cases = [
+            (Error::BadPath("/x".to_string()), StatusCode::BAD_REQUEST),
+            (Error::BadHeader("content-length"), StatusCode::BAD_REQUEST),
+            (Error::OriginBusy, StatusCode::TOO_MANY_REQUESTS),
+            (
+                Error::bad_upstream(&url, "status 500"),
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                Error::Timeout {
+                    url: url.to_string(),
+                    after: Duration::from_secs(1),
+                },
+                StatusCode::GATEWAY_TIMEOUT,
+            ),
+            (Error::from(builder), StatusCode::BAD_GATEWAY),
+        ];
+        for (err, status) in cases {
+            assert_eq!(err.status(), status, "{err}");
+            assert_eq!(err.into_response().status(), status);
+        }
+    }
+
+    #[test]
+    fn client_errors_explain_themselves() {
+        let r = Error::BadHeader("content-length").into_response();
+        assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+        let r = Error::OriginBusy.into_response();
+        assert_eq!(r.headers()["retry-after"], "1");
+    }
+
+    /// Starts a server that accepts a connection and never answers.
+    pub(crate) async fn silent_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn reqwest_timeouts_are_gateway_timeouts() {
+        let addr = silent_upstream().await;
+        let err = reqwest::Client::builder()
+            .timeout(Duration::from_millis(50))
+            .build()
+            .unwrap()
+            .get(format!("http://{addr}/"))
+            .send()
+            .await
+            .unwrap_err();
+        assert_eq!(Error::from(err).status(), StatusCode::GATEWAY_TIMEOUT);
     }
 }
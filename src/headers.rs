@@ -0,0 +1,217 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::bail;
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{HeaderMap, HeaderName, header},
+};
+use http_body::{Frame, SizeHint};
+use serde::Deserialize;
+
+/// Origin response headers that describe the body and are always passed on.
+const PASS: [HeaderName; 4] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_ENCODING,
+    header::ETAG,
+    header::LAST_MODIFIED,
+];
+
+/// Headers that only apply to a single connection, or that the gateway sets
+/// itself to frame the body.
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Headers added by whatever served a response, rather than describing it.
+const SERVER_DETAILS: [HeaderName; 3] = [header::SET_COOKIE, header::SERVER, header::VIA];
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Headers {
+    /// Extra origin response headers to pass on to clients.
+    pass: Vec<String>,
+}
+
+impl Headers {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for name in &self.pass {
+            let Ok(name) = HeaderName::try_from(name.as_str()) else {
+                bail!("headers.pass: invalid header name {name:?}");
+            };
+            if HOP_BY_HOP.contains(&name.as_str()) || name == header::CONTENT_LENGTH {
+                bail!("headers.pass: {name} is set by the gateway");
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps the origin headers that describe the body, dropping cookies,
+    /// server and CDN details, and framing.
+    pub fn proxied(&self, upstream: &HeaderMap) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let extra = self
+            .pass
+            .iter()
+            .filter_map(|name| HeaderName::try_from(name.as_str()).ok());
+        for name in PASS.into_iter().chain(extra) {
+            for value in upstream.get_all(&name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+        headers
+    }
+}
+
+/// Copies the headers of a response relayed from a peer, except those that
+/// only apply to its connection or framing, or that a proxy in front of the
+/// peer may have added.
+pub fn relayed(upstream: &HeaderMap) -> HeaderMap {
+    let mut headers = upstream.clone();
+    for name in HOP_BY_HOP.into_iter().chain(["content-length"]) {
+        headers.remove(name);
+    }
+    for name in SERVER_DETAILS {
+        headers.remove(name);
+    }
+    headers
+}
+
+/// A response body of `len` bytes if known, so that the server frames it
+/// with `content-length` and aborts the response if the stream ends early.
+/// Otherwise the body is sent chunked.
+pub fn body(inner: Body, len: Option<u64>) -> Body {
+    Body::new(SizedBody { inner, len })
+}
+
+struct SizedBody {
+    inner: Body,
+    len: Option<u64>,
+}
+
+impl HttpBody for SizedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.len
+            .map_or_else(|| self.inner.size_hint(), SizeHint::with_exact)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn headers(pass: &[&str]) -> Headers {
+        Headers {
+            pass: pass.iter().map(|&name| name.to_owned()).collect(),
+        }
+    }
+
+    fn upstream() -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in [
+            ("content-type", "application/x-nix-nar"),
+            ("content-encoding", "zstd"),
+            ("etag", "\"abc\""),
+            ("last-modified", "Sat, 17 Oct 2026 12:00:00 GMT"),
+            ("cache-control", "public, max-age=60"),
+            ("link", "<https://a.example/>; rel=preload"),
+            ("link", "<https://b.example/>; rel=preload"),
+            ("set-cookie", "session=1"),
+            ("server", "nginx"),
+            ("via", "1.1 cdn"),
+            ("transfer-encoding", "chunked"),
+            ("connection", "keep-alive"),
+            ("content-length", "42"),
+        ] {
+            map.append(name, HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    const DROPPED: [&str; 6] = [
+        "set-cookie",
+        "server",
+        "via",
+        "transfer-encoding",
+        "connection",
+        "content-length",
+    ];
+
+    #[test]
+    fn proxied_keeps_only_body_headers() {
+        let proxied = headers(&[]).proxied(&upstream());
+        let names: Vec<_> = proxied.keys().map(HeaderName::as_str).collect();
+        assert_eq!(
+            names,
+            ["content-type", "content-encoding", "etag", "last-modified"]
+        );
+    }
+
+    #[test]
+    fn proxied_passes_extras_with_every_value() {
+        let proxied = headers(&["Link", "Cache-Control", "X-Missing"]).proxied(&upstream());
+        let links: Vec<_> = proxied.get_all(header::LINK).iter().collect();
+        assert_eq!(
+            links,
+            [
+                "<https://a.example/>; rel=preload",
+                "<https://b.example/>; rel=preload"
+            ]
+        );
+        assert_eq!(proxied[header::CACHE_CONTROL], "public, max-age=60");
+        assert!(!proxied.contains_key("x-missing"));
+        for name in DROPPED {
+            assert!(!proxied.contains_key(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn relayed_drops_connection_and_server_details() {
+        let relayed = relayed(&upstream());
+        for name in DROPPED {
+            assert!(!relayed.contains_key(name), "{name}");
+        }
+        assert_eq!(relayed.get_all(header::LINK).iter().count(), 2);
+        for name in ["content-type", "content-encoding", "etag", "cache-control"] {
+            assert!(relayed.contains_key(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn validate_rejects_gateway_headers() {
+        assert!(headers(&["Link", "x-amz-meta-source"]).validate().is_ok());
+        for name in HOP_BY_HOP
+            .into_iter()
+            .chain(["Content-Length", "Transfer-Encoding"])
+        {
+            assert!(headers(&[name]).validate().is_err(), "{name}");
+        }
+        assert!(headers(&["bad header"]).validate().is_err());
+    }
+}
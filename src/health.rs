@@ -0,0 +1,42 @@
+use axum::{
+    Json, Router,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde_json::json;
+
+use crate::AppState;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/healthz", get(|| async { "ok\n" }))
+        .route("/readyz", get(readyz))
+        .route("/_status", get(status))
+}
+
+async fn readyz(State(app): State<AppState>) -> Response {
+    let health = app.health().await;
+    if health.ready() {
+        return "ok\n".into_response();
+    }
+    let failed = health
+        .upstreams
+        .iter()
+        .filter_map(|u| Some(format!("{} {}: {}\n", u.kind, u.url, u.error.as_ref()?)))
+        .collect::<String>();
+    (StatusCode::SERVICE_UNAVAILABLE, failed).into_response()
+}
+
+async fn status(State(app): State<AppState>) -> Response {
+    let health = app.health().await;
+    Json(json!({
+        "ready": health.ready(),
+        "checked_at": health.checked_at,
+        "upstreams": health.upstreams,
+        "cache_entries": app.cache_entries(),
+        "uploads_in_flight": app.uploads().len(),
+    }))
+    .into_response()
+}
@@ -0,0 +1,198 @@
+use std::sync::LazyLock;
+
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics::counter;
+use regex::RegexSet;
+use serde::Deserialize;
+
+use crate::AppState;
+
+/// Longest key accepted; real store keys are well under 200 bytes.
+const MAX_KEY_LEN: usize = 1024;
+
+/// Key shapes of a Nix binary cache. Store path hashes use Nix's base32
+/// alphabet, which leaves out `e`, `o`, `t` and `u`.
+const BUILTIN: [&str; 6] = [
+    r"nix-cache-info",
+    r"[0-9a-df-np-sv-z]{32}\.(narinfo|ls)",
+    r"nar/[0-9a-df-np-sv-z]{52}\.nar(\.(xz|bz2|zst|lz4|lzip|br|gz))?",
+    r"log/[0-9a-df-np-sv-z]{32}-[0-9A-Za-z+\-._?=]+\.drv",
+    r"debuginfo/[0-9a-f]+",
+    r"realisations/[0-9a-z]+:[0-9a-z]+![0-9A-Za-z+\-._?=]+\.doi",
+];
+
+static BUILTIN_SET: LazyLock<RegexSet> =
+    LazyLock::new(|| compile(&BUILTIN).expect("built-in key patterns are valid"));
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Keys {
+    /// Extra regular expressions for keys to accept, matched against the
+    /// whole key without its leading `/`.
+    extra_patterns: Vec<String>,
+}
+
+impl Keys {
+    pub fn compile(&self) -> anyhow::Result<KeyFilter> {
+        let extra = compile(&self.extra_patterns)
+            .map_err(|err| anyhow::anyhow!("keys.extra_patterns: {err}"))?;
+        Ok(KeyFilter { extra })
+    }
+}
+
+fn compile<S: AsRef<str>>(patterns: &[S]) -> Result<RegexSet, regex::Error> {
+    RegexSet::new(patterns.iter().map(|p| format!("^(?:{})$", p.as_ref())))
+}
+
+/// Decides which request paths are store keys worth asking upstreams for.
+pub struct KeyFilter {
+    extra: RegexSet,
+}
+
+impl KeyFilter {
+    pub fn allows(&self, path: &str) -> bool {
+        let Some(key) = path.strip_prefix('/') else {
+            return false;
+        };
+        // Structural checks apply to extra patterns too, so that no pattern
+        // can let a key escape the upstream base URLs.
+        // Keys starting with `_` are reserved for the gateway's own objects,
+        // such as negative markers.
+        if key.len() > MAX_KEY_LEN
+            || key.starts_with('_')
+            || key.contains(['%', '\\', '?', '#'])
+            || key
+                .split('/')
+                .any(|segment| matches!(segment, "" | "." | ".."))
+        {
+            return false;
+        }
+        BUILTIN_SET.is_match(key) || self.extra.is_match(key)
+    }
+}
+
+/// Rejects requests for paths that are not store keys before any upstream is
+/// asked.
+pub async fn enforce(State(app): State<AppState>, request: Request, next: Next) -> Response {
+    if request.uri().query().is_some() || !app.key_allowed(request.uri().path()) {
+        counter!("nix_store_gateway_invalid_key").increment(1);
+        return (StatusCode::BAD_REQUEST, "invalid store key\n").into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0bpmnwjs5gf5yqwxkh5a9zrdc8a3pbi5";
+    const FILE_HASH: &str = "1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3";
+
+    fn filter(extra_patterns: &[&str]) -> KeyFilter {
+        Keys {
+            extra_patterns: extra_patterns.iter().map(ToString::to_string).collect(),
+        }
+        .compile()
+        .unwrap()
+    }
+
+    #[test]
+    fn accepts_store_keys() {
+        let filter = filter(&[]);
+        for key in [
+            "/nix-cache-info".to_string(),
+            format!("/{HASH}.narinfo"),
+            format!("/{HASH}.ls"),
+            format!("/nar/{FILE_HASH}.nar"),
+            format!("/nar/{FILE_HASH}.nar.xz"),
+            format!("/nar/{FILE_HASH}.nar.zst"),
+            format!("/nar/{FILE_HASH}.nar.bz2"),
+            format!("/log/{HASH}-hello-2.12.1.drv"),
+            "/debuginfo/0123456789abcdef0123456789abcdef01234567".to_string(),
+            format!("/realisations/sha256:{FILE_HASH}!out.doi"),
+        ] {
+            assert!(filter.allows(&key), "{key}");
+        }
+    }
+
+    #[test]
+    fn rejects_other_keys() {
+        let filter = filter(&[]);
+        let hash_with = |c: char| format!("/{}{c}.narinfo", &HASH[1..]);
+        for key in [
+            String::new(),
+            "/".to_string(),
+            format!("{HASH}.narinfo"),
+            format!("/{HASH}.narinfo?x=1"),
+            format!("/{HASH}.narinfo#x"),
+            format!("/../{HASH}.narinfo"),
+            format!("/nar/../{HASH}.narinfo"),
+            format!("/./{HASH}.narinfo"),
+            format!("//{HASH}.narinfo"),
+            format!("/nar//{FILE_HASH}.nar"),
+            format!("/nar/{FILE_HASH}.nar/"),
+            format!("/nar%2F{FILE_HASH}.nar"),
+            format!("/%30{}.narinfo", &HASH[1..]),
+            format!("/nar\\{FILE_HASH}.nar"),
+            format!("/{}.narinfo", &HASH[1..]),
+            format!("/{HASH}0.narinfo"),
+            format!("/{}.narinfo", HASH.to_uppercase()),
+            format!("/nar/{FILE_HASH}.nar.rar"),
+            format!("/nar/{HASH}.nar"),
+            format!("/log/{HASH}.drv"),
+            "/debuginfo/xyz".to_string(),
+            "/index.html".to_string(),
+            "/_negative/x".to_string(),
+            hash_with('e'),
+            hash_with('o'),
+            hash_with('u'),
+            hash_with('t'),
+        ] {
+            assert!(!filter.allows(&key), "{key}");
+        }
+    }
+
+    #[test]
+    fn extra_patterns_cannot_bypass_structural_checks() {
+        let filter = filter(&[".*"]);
+        assert!(filter.allows("/anything/goes.json"));
+        for key in [
+            "/a/../b",
+            "/a//b",
+            "/a/./b",
+            "/a%2Fb",
+            "/a\\b",
+            "/a?b",
+            "/a#b",
+            "a",
+            "/_negative/x",
+            "/_x",
+        ] {
+            assert!(!filter.allows(key), "{key}");
+        }
+        let long = format!("/{}", "a".repeat(MAX_KEY_LEN + 1));
+        assert!(!filter.allows(&long));
+        assert!(filter.allows(&long[..=MAX_KEY_LEN]));
+    }
+
+    #[test]
+    fn extra_patterns_match_whole_keys() {
+        let filter = filter(&[r"nix-cache/[0-9a-z]+\.json"]);
+        assert!(filter.allows("/nix-cache/abc.json"));
+        assert!(!filter.allows("/nix-cache/abc.json.bak"));
+        assert!(!filter.allows("/x/nix-cache/abc.json"));
+    }
+
+    #[test]
+    fn invalid_extra_patterns_are_rejected() {
+        let keys = Keys {
+            extra_patterns: vec!["(".to_string()],
+        };
+        assert!(keys.compile().is_err());
+    }
+}
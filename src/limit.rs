@@ -0,0 +1,313 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics::counter;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::AppState;
+use crate::proxy::ClientIp;
+
+#[derive(Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Limits {
+    /// Token bucket per client IP address.
+    pub per_ip: Option<Rate>,
+    /// Token bucket per `Authorization` header, for clients that send one.
+    pub per_token: Option<Rate>,
+    /// Maximum number of concurrent origin fetches across all clients.
+    pub origin_concurrency: Option<usize>,
+}
+
+impl Limits {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for rate in [self.per_ip, self.per_token].into_iter().flatten() {
+            if rate.rate == 0 || rate.burst == 0 {
+                anyhow::bail!("limits rate and burst must be positive");
+            }
+        }
+        if self.origin_concurrency == Some(0) {
+            anyhow::bail!("limits.origin_concurrency must be positive");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    /// Sustained requests per second.
+    pub rate: u32,
+    /// Requests allowed at once before the sustained rate applies.
+    pub burst: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(rate.rate)).min(f64::from(rate.burst));
+        self.updated = now;
+    }
+}
+
+/// Token buckets keyed by client.
+struct Buckets<K> {
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K: Hash + Eq> Buckets<K> {
+    fn new() -> Self {
+        Self {
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Takes a token for `key`, or returns how long until one is available.
+    fn take(&self, key: K, rate: Rate, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: f64::from(rate.burst),
+            updated: now,
+        });
+        bucket.refill(rate, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / f64::from(rate.rate),
+            ))
+        }
+    }
+
+    /// Forgets clients whose buckets are full again, as they are
+    /// indistinguishable from new ones.
+    fn prune(&self, rate: Option<Rate>, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let Some(rate) = rate else {
+            buckets.clear();
+            return;
+        };
+        buckets.retain(|_, b| {
+            b.refill(rate, now);
+            b.tokens < f64::from(rate.burst)
+        });
+    }
+}
+
+pub struct Limiter {
+    per_ip: Buckets<IpAddr>,
+    per_token: Buckets<[u8; 32]>,
+    origin_concurrency: Option<usize>,
+    origin: Arc<Semaphore>,
+}
+
+impl Limiter {
+    pub fn new(limits: &Limits) -> Self {
+        Self {
+            per_ip: Buckets::new(),
+            per_token: Buckets::new(),
+            origin_concurrency: limits.origin_concurrency,
+            origin: Arc::new(Semaphore::new(
+                limits.origin_concurrency.unwrap_or(Semaphore::MAX_PERMITS),
+            )),
+        }
+    }
+
+    pub fn origin_concurrency(&self) -> Option<usize> {
+        self.origin_concurrency
+    }
+
+    /// Returns the scope that rejected the request and when to retry.
+    pub fn check(
+        &self,
+        limits: &Limits,
+        ip: Option<IpAddr>,
+        token: Option<[u8; 32]>,
+    ) -> Result<(), (&'static str, Duration)> {
+        let now = Instant::now();
+        if let (Some(rate), Some(ip)) = (limits.per_ip, ip) {
+            self.per_ip.take(ip, rate, now).map_err(|d| ("ip", d))?;
+        }
+        if let (Some(rate), Some(token)) = (limits.per_token, token) {
+            self.per_token
+                .take(token, rate, now)
+                .map_err(|d| ("token", d))?;
+        }
+        Ok(())
+    }
+
+    /// Reserves an origin fetch, or returns `None` if too many are running.
+    pub fn origin_permit(&self) -> Option<OwnedSemaphorePermit> {
+        self.origin.clone().try_acquire_owned().ok()
+    }
+
+    pub fn prune(&self, limits: &Limits) {
+        let now = Instant::now();
+        self.per_ip.prune(limits.per_ip, now);
+        self.per_token.prune(limits.per_token, now);
+    }
+}
+
+/// Applies the per-client rate limits.
+pub async fn enforce(State(app): State<AppState>, request: Request, next: Next) -> Response {
+    let ip = request.extensions().get::<ClientIp>().map(|c| c.0);
+    // Only a digest of the credentials is kept.
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .map(|v| Sha256::digest(v.as_bytes()).into());
+    if let Err((scope, retry_after)) = app.rate_limit(ip, token) {
+        counter!("nix_store_gateway_rate_limited", "scope" => scope).increment(1);
+        return too_many_requests(retry_after);
+    }
+    next.run(request).await
+}
+
+pub fn too_many_requests(retry_after: Duration) -> Response {
+    let secs = (retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)).max(1);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, secs.to_string())],
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: Rate = Rate { rate: 2, burst: 3 };
+
+    fn retry_after(retry_after: Duration) -> String {
+        let response = too_many_requests(retry_after);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        response.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn burst_then_reject() {
+        let buckets = Buckets::new();
+        let now = Instant::now();
+        for _ in 0..RATE.burst {
+            assert_eq!(buckets.take(1, RATE, now), Ok(()));
+        }
+        assert_eq!(buckets.take(1, RATE, now), Err(Duration::from_millis(500)));
+        // Buckets are per key.
+        assert_eq!(buckets.take(2, RATE, now), Ok(()));
+    }
+
+    #[test]
+    fn refills_at_the_sustained_rate() {
+        let buckets = Buckets::new();
+        let now = Instant::now();
+        for _ in 0..RATE.burst {
+            buckets.take(1, RATE, now).unwrap();
+        }
+        let later = now + Duration::from_millis(250);
+        assert_eq!(
+            buckets.take(1, RATE, later),
+            Err(Duration::from_millis(250))
+        );
+        let later = now + Duration::from_millis(500);
+        assert_eq!(buckets.take(1, RATE, later), Ok(()));
+        assert!(buckets.take(1, RATE, later).is_err());
+        // Idle time never banks more than the burst.
+        let later = later + Duration::from_mins(1);
+        for _ in 0..RATE.burst {
+            assert_eq!(buckets.take(1, RATE, later), Ok(()));
+        }
+        assert!(buckets.take(1, RATE, later).is_err());
+    }
+
+    #[test]
+    fn retry_after_is_never_zero() {
+        for (after, expected) in [
+            (Duration::ZERO, "1"),
+            (Duration::from_millis(1), "1"),
+            (Duration::from_secs(1), "1"),
+            (Duration::from_millis(1500), "2"),
+            (Duration::from_secs(3), "3"),
+        ] {
+            assert_eq!(retry_after(after), expected, "{after:?}");
+        }
+    }
+
+    #[test]
+    fn prune_forgets_full_buckets() {
+        let buckets = Buckets::new();
+        let now = Instant::now();
+        buckets.take(1, RATE, now).unwrap();
+        buckets
+            .take(2, RATE, now + Duration::from_millis(400))
+            .unwrap();
+
+        buckets.prune(Some(RATE), now + Duration::from_millis(500));
+        let kept: Vec<_> = buckets.buckets.lock().unwrap().keys().copied().collect();
+        assert_eq!(kept, [2]);
+
+        buckets
+            .take(1, RATE, now + Duration::from_millis(500))
+            .unwrap();
+        buckets.prune(None, now + Duration::from_millis(500));
+        assert!(buckets.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn validate_rejects_zeroes() {
+        let rate = |rate, burst| Some(Rate { rate, burst });
+        assert!(Limits::default().validate().is_ok());
+        assert!(
+            Limits {
+                per_ip: rate(1, 1),
+                per_token: rate(10, 100),
+                origin_concurrency: Some(1),
+            }
+            .validate()
+            .is_ok()
+        );
+        for limits in [
+            Limits {
+                per_ip: rate(0, 1),
+                ..Limits::default()
+            },
+            Limits {
+                per_ip: rate(1, 0),
+                ..Limits::default()
+            },
+            Limits {
+                per_token: rate(0, 1),
+                ..Limits::default()
+            },
+            Limits {
+                per_token: rate(1, 0),
+                ..Limits::default()
+            },
+            Limits {
+                origin_concurrency: Some(0),
+                ..Limits::default()
+            },
+        ] {
+            assert!(limits.validate().is_err());
+        }
+    }
+}
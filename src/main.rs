@@ -1,102 +1,383 @@
 #![warn(clippy::pedantic)]
 
-use std::{future::ready, sync::Arc, time::Duration};
+use std::{
+    convert::Infallible,
+    future::ready,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
+use anyhow::bail;
 use axum::{
-    Router,
-    extract::{Request, State},
-    http::status::StatusCode,
+    Extension, Router,
+    extract::{MatchedPath, Request, State},
+    http::{HeaderMap, HeaderValue, header, status::StatusCode},
+    middleware::{self, Next},
     response::{IntoResponse, Redirect, Response},
     routing::get,
 };
-use bytes::Bytes;
-use metrics::counter;
+use clap::Parser;
+use metrics::{counter, gauge, histogram};
 use metrics_exporter_prometheus::PrometheusBuilder;
-use tokio::{net::TcpListener, sync::mpsc, time::interval};
-use tokio_stream::StreamExt;
+use tokio::{
+    net::TcpListener,
+    signal::unix::{SignalKind, signal},
+    time::Instant,
+    time::interval,
+};
 use tower_http::trace::TraceLayer;
 use url::Url;
 
+mod access_log;
+mod acl;
+mod admin;
 mod app;
+mod cache;
+mod cli;
+mod cluster;
 mod error;
+mod headers;
+mod health;
+mod key;
+mod limit;
+mod nix;
+mod proxy;
 mod sign;
+mod telemetry;
+mod upload;
 
-use crate::app::{App, Config};
+use crate::access_log::{AccessLogger, Decision, Outcome};
+use crate::app::{App, Config, Provenance};
+use crate::cli::{Cli, Command};
 use crate::error::Error;
+use crate::proxy::{ClientIp, PeerAddr, ProxyListener};
 
 type AppState = Arc<App>;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-
-    if std::env::args().len() != 3 {
-        eprintln!(
-            "Usage: {} <listen> <config.toml>",
-            std::env::args().next().unwrap()
-        );
-        std::process::exit(1);
+    match Cli::parse().command {
+        Command::Serve {
+            listen,
+            config,
+            shutdown_timeout,
+        } => serve(&listen, config, Duration::from_secs(shutdown_timeout)).await,
+        Command::CheckConfig { config } => {
+            telemetry::init(None)?;
+            check_config(&config).await
+        }
+        Command::Admin { remote, command } => {
+            telemetry::init(None)?;
+            command.run(remote).await
+        }
     }
+}
+
+async fn serve(addr: &str, config_path: PathBuf, shutdown_timeout: Duration) -> anyhow::Result<()> {
+    let config = Config::load(&config_path)?;
+    let tracer = telemetry::init(config.telemetry())?;
+    let listener = TcpListener::bind(addr).await?;
+    let access_log = AccessLogger::open(config.access_log()).await?;
+    let proxy = Arc::new(config.proxy().clone());
 
-    let addr = std::env::args().nth(1).unwrap();
-    let config = Config::load(std::env::args().nth(2).unwrap())?;
-    let listener = TcpListener::bind(&addr).await?;
+    let prometheus = PrometheusBuilder::new()
+        .set_buckets(&[
+            0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+        ])?
+        .install_recorder()?;
+    let state = AppState::new(App::from_config(config).await?);
+    let listener = ProxyListener::new(listener, proxy.clone(), state.clone())?;
 
-    let prometheus = PrometheusBuilder::new().install_recorder().unwrap();
     let m = prometheus.clone();
+    let a = state.clone();
     tokio::spawn(async move {
         let mut interval = interval(Duration::from_secs(5));
         loop {
             interval.tick().await;
+            a.prune_cache().await;
+            #[allow(clippy::cast_precision_loss)]
+            gauge!("nix_store_gateway_lookup_cache_entries").set(a.cache_entries() as f64);
             m.run_upkeep();
+            a.prune_rate_limits();
         }
     });
 
-    let state = App::from_config(config)?;
-    let app = Router::new()
+    tokio::spawn(watch_config(config_path, state.clone()));
+    let app = store_routes(&state)
         .route("/metrics", get(move || ready(prometheus.render())))
+        .merge(health::router())
+        .nest("/_admin", admin::router(state.clone()))
+        .with_state(state.clone())
+        .layer(middleware::from_fn(observe_request))
+        .layer(middleware::from_fn_with_state(
+            access_log,
+            access_log::record,
+        ))
+        .layer(middleware::from_fn_with_state(proxy, proxy::resolve))
+        .layer(TraceLayer::new_for_http().make_span_with(telemetry::make_span));
+
+    // Stop accepting connections on SIGTERM/SIGINT and give in-flight
+    // responses and background uploads `shutdown_timeout` to finish.
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel();
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<PeerAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        tracing::info!("shutting down");
+        let _ = stop_tx.send(Instant::now() + shutdown_timeout);
+    });
+    let mut server = std::pin::pin!(server.into_future());
+    let deadline = tokio::select! {
+        res = &mut server => {
+            res?;
+            Instant::now() + shutdown_timeout
+        }
+        Ok(deadline) = stop_rx => {
+            if tokio::time::timeout_at(deadline, &mut server).await.is_err() {
+                tracing::warn!("client responses did not finish before the shutdown deadline");
+            }
+            deadline
+        }
+    };
+
+    let abandoned = state
+        .drain(deadline.saturating_duration_since(Instant::now()))
+        .await;
+    counter!("nix_store_gateway_shutdown_abandoned_uploads").increment(abandoned as u64);
+    if abandoned > 0 {
+        tracing::warn!("abandoned {} background upload(s)", abandoned);
+    } else {
+        tracing::info!("all background uploads finished");
+    }
+    if let Some(tracer) = tracer {
+        tokio::task::spawn_blocking(move || tracer.shutdown()).await??;
+    }
+    Ok(())
+}
+
+/// Routes serving the binary cache, behind ACLs, rate limits and the key
+/// filter. Only store keys are forwarded to their cluster owner.
+fn store_routes(state: &AppState) -> Router<AppState> {
+    Router::new()
         .route(
             "/nix-cache-info",
-            get(|| ready("StoreDir: /nix/store\nWantMassQuery: 1\nPriority: 30\n")),
+            get(|| {
+                ready((
+                    [
+                        (header::CONTENT_TYPE, nix::content_type("/nix-cache-info")),
+                        (header::CACHE_CONTROL, nix::cache_control("/nix-cache-info")),
+                    ],
+                    "StoreDir: /nix/store\nWantMassQuery: 1\nPriority: 30\n",
+                ))
+            }),
+        )
+        .route(
+            "/{*key}",
+            get(fetch)
+                .head(check)
+                .put(upload)
+                .delete(delete)
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    cluster::forward,
+                )),
         )
-        .route("/{*key}", get(fetch).head(check).put(upload).delete(delete))
-        .with_state(AppState::new(state))
-        .layer(TraceLayer::new_for_http());
-    axum::serve(listener, app).await?;
+        .route_layer(middleware::from_fn_with_state(state.clone(), key::enforce))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            limit::enforce,
+        ))
+        .route_layer(middleware::from_fn_with_state(state.clone(), acl::enforce))
+}
+
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(v) => v,
+        Err(err) => {
+            tracing::error!("cannot install SIGTERM handler: {:?}", err);
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+async fn check_config(path: &Path) -> anyhow::Result<()> {
+    let mut config = Config::load(path)?;
+    // A running instance may hold the lock on the cache file.
+    config.disable_persistence();
+    let app = App::from_config(config).await?;
+
+    let statuses = app.check_upstreams().await;
+    let failed = statuses.iter().filter(|s| s.error.is_some()).count();
+    for status in statuses {
+        match status.error {
+            None => println!("ok    {:<6} {}", status.kind, status.url),
+            Some(err) => println!("FAIL  {:<6} {}: {}", status.kind, status.url, err),
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} upstream check(s) failed");
+    }
     Ok(())
 }
 
-async fn check(State(app): State<AppState>, request: Request) -> Response {
-    let u = app.get_mirror(request.uri().path()).await;
-    if let Some(u) = u {
-        return Response::builder()
-            .status(StatusCode::OK)
-            .header("location", u)
-            .body(axum::body::Body::empty())
-            .unwrap();
+/// Reloads the configuration on SIGHUP or when the file changes.
+async fn watch_config(path: PathBuf, app: AppState) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(v) => v,
+        Err(err) => {
+            tracing::error!("cannot install SIGHUP handler: {:?}", err);
+            return;
+        }
+    };
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified(&path);
+    let mut interval = interval(Duration::from_secs(5));
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {}
+            _ = interval.tick() => {
+                if modified(&path) == last_modified {
+                    continue;
+                }
+            }
+        }
+        last_modified = modified(&path);
+
+        let reloaded = match Config::load(&path) {
+            Ok(config) => app.reload(config).await,
+            Err(err) => Err(err),
+        };
+        match reloaded {
+            Ok(()) => {
+                tracing::info!("reloaded configuration from {}", path.display());
+                counter!("nix_store_gateway_config_reload", "result" => "success").increment(1);
+                #[allow(clippy::cast_precision_loss)]
+                gauge!("nix_store_gateway_config_last_reload_timestamp_seconds").set(
+                    SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs() as f64,
+                );
+            }
+            Err(err) => {
+                tracing::error!("failed to reload {}: {:?}", path.display(), err);
+                counter!("nix_store_gateway_config_reload", "result" => "failure").increment(1);
+            }
+        }
     }
+}
 
-    let o = app.get_origin(request.uri().path()).await;
-    if let Some((u, _)) = o {
-        return Response::builder()
-            .status(StatusCode::OK)
-            .header("location", u)
-            .body(axum::body::Body::empty())
-            .unwrap();
+/// Records request latency per route and method.
+async fn observe_request(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |p| p.as_str().to_string());
+    let method = request.method().to_string();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    histogram!(
+        "nix_store_gateway_request_duration_seconds",
+        "route" => route,
+        "method" => method,
+        "status" => response.status().as_u16().to_string(),
+    )
+    .record(start.elapsed());
+    response
+}
+
+async fn check(State(app): State<AppState>, request: Request) -> Result<Response, Error> {
+    let path = request.uri().path();
+    if app.write_config().enabled {
+        return check_store(&app, path).await;
+    }
+
+    let u = app.get_mirror(path).await?;
+    if let Some((u, decision)) = u {
+        counter!("nix_store_gateway_check", "type" => "mirror").increment(1);
+        return Ok(found(&app, path, u, decision));
     }
 
-    StatusCode::NOT_FOUND.into_response()
+    let decision = match app.get_origin(path).await? {
+        Ok((u, _, _)) => {
+            counter!("nix_store_gateway_check", "type" => "origin").increment(1);
+            return Ok(found(&app, path, u, Decision::Origin));
+        }
+        Err(decision) => decision,
+    };
+
+    counter!("nix_store_gateway_check", "type" => "not_found").increment(1);
+    Ok(not_found(&app, decision))
 }
 
-async fn fetch(State(app): State<AppState>, request: Request) -> Response {
-    let u = app.get_mirror(request.uri().path()).await;
-    if let Some(url) = u {
-        if let Some(host) = Url::parse(&url)
-            .ok()
-            .as_ref()
-            .and_then(|u| u.host_str())
-            .map(str::to_string)
-        {
+/// HEAD response for a path available at `url`.
+fn found(app: &App, path: &str, url: String, decision: Decision) -> Response {
+    let outcome = Outcome {
+        decision: Some(decision),
+        upstream: host(&url),
+        upload: false,
+    };
+    (
+        [
+            (header::LOCATION, url),
+            (header::CONTENT_TYPE, nix::content_type(path).to_string()),
+            (header::CACHE_CONTROL, max_age(app.max_age(decision))),
+        ],
+        Extension(outcome),
+    )
+        .into_response()
+}
+
+/// HEAD for `nix copy`, which uploads whatever our S3 store is missing. The
+/// answer changes as soon as the client uploads, so it must not be cached.
+async fn check_store(app: &App, path: &str) -> Result<Response, Error> {
+    let Some(object) = app.stat(path).await? else {
+        counter!("nix_store_gateway_check", "type" => "not_found").increment(1);
+        let outcome = Outcome {
+            decision: Some(Decision::Miss),
+            ..Outcome::default()
+        };
+        return Ok((
+            StatusCode::NOT_FOUND,
+            [(header::CACHE_CONTROL, "no-cache")],
+            Extension(outcome),
+        )
+            .into_response());
+    };
+    counter!("nix_store_gateway_check", "type" => "store").increment(1);
+    let outcome = Outcome {
+        decision: Some(Decision::S3),
+        ..Outcome::default()
+    };
+    let mut r = (
+        [
+            (header::CONTENT_TYPE, nix::content_type(path)),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        Extension(outcome),
+    )
+        .into_response();
+    for name in [header::ETAG, header::LAST_MODIFIED] {
+        if let Some(v) = object.get(&name) {
+            r.headers_mut().insert(name, v.clone());
+        }
+    }
+    Ok(r)
+}
+
+async fn fetch(State(app): State<AppState>, request: Request) -> Result<Response, Error> {
+    let path = request.uri().path();
+    let u = app.get_mirror(path).await?;
+    if let Some((url, decision)) = u {
+        let host = host(&url);
+        if let Some(host) = host.clone() {
             counter!(
                 "nix_store_gateway_fetch",
                 "type" => "mirror",
@@ -105,90 +386,230 @@ async fn fetch(State(app): State<AppState>, request: Request) -> Response {
             .increment(1);
         }
 
-        return Redirect::temporary(&url).into_response();
+        let outcome = Outcome {
+            decision: Some(decision),
+            upstream: host,
+            upload: false,
+        };
+        return Ok((
+            [(header::CACHE_CONTROL, max_age(app.max_age(decision)))],
+            Extension(outcome),
+            Redirect::temporary(&url),
+        )
+            .into_response());
     }
 
-    let o = app.get_origin(request.uri().path()).await;
-    if let Some((u, resp)) = o {
-        if let Some(host) = Url::parse(&u)
-            .ok()
-            .as_ref()
-            .and_then(|u| u.host_str())
-            .map(str::to_string)
-        {
-            counter!(
-                "nix_store_gateway_fetch",
-                "type" => "origin",
-                "host" => host,
-            )
-            .increment(1);
-        }
+    let decision = match app.get_origin(path).await? {
+        Ok((u, resp, permit)) => {
+            let host = host(&u);
+            if let Some(host) = host.clone() {
+                counter!(
+                    "nix_store_gateway_fetch",
+                    "type" => "origin",
+                    "host" => host,
+                )
+                .increment(1);
+            }
 
-        let headers = resp.headers().clone();
-        let size = headers
-            .get("content-length")
-            .map(|v| v.to_str().unwrap().parse().unwrap());
-
-        let (tx, rx) = mpsc::channel::<reqwest::Result<Bytes>>(64);
-        let (tx2, rx2) = mpsc::channel::<Result<Bytes, Error>>(64);
-        tokio::spawn(async move {
-            let mut b = resp.bytes_stream();
-            while let Some(v) = b.next().await {
-                match v {
-                    Ok(buf) => {
-                        if tx.send(Ok(buf.clone())).await.is_ok() {
-                            let _ = tx2.send(Ok(buf)).await;
-                        } else {
-                            let _ = tx2.send(Err(Error::new("send error"))).await;
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        let _ = tx2.send(Err(Error::new(e.to_string()))).await;
-                        let _ = tx.send(Err(e)).await;
-                        break;
-                    }
-                }
+            let mut headers = app.proxied_headers(resp.headers());
+            let len = resp.content_length();
+            let content_type = nix::content_type(path);
+            if content_type != "application/octet-stream"
+                || !headers.contains_key(header::CONTENT_TYPE)
+            {
+                headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+            }
+            headers.insert(
+                header::CACHE_CONTROL,
+                HeaderValue::from_static(nix::cache_control(path)),
+            );
+            if !headers.contains_key(header::ETAG)
+                && let Some(etag) = nix::etag(path).and_then(|v| HeaderValue::try_from(v).ok())
+            {
+                headers.insert(header::ETAG, etag);
             }
-        });
-        tokio::spawn(app.upload(
-            request.uri().path(),
-            size,
-            tokio_stream::wrappers::ReceiverStream::new(rx2),
-        ));
+            let (body, upload) = app.tee(path, &u, resp, permit).await;
 
-        let mut r = Response::new(axum::body::Body::from_stream(
-            tokio_stream::wrappers::ReceiverStream::new(rx),
-        ));
-        *r.headers_mut() = headers;
+            let body = axum::body::Body::from_stream(body);
+            let mut r = Response::new(headers::body(body, len));
+            *r.headers_mut() = headers;
+            r.extensions_mut().insert(Outcome {
+                decision: Some(Decision::Origin),
+                upstream: host,
+                upload,
+            });
 
-        return r;
-    }
+            return Ok(r);
+        }
+        Err(decision) => decision,
+    };
 
     counter!("nix_store_gateway_fetch", "type" => "not_found").increment(1);
-    StatusCode::NOT_FOUND.into_response()
+    Ok(not_found(&app, decision))
 }
 
-async fn upload(State(app): State<AppState>, request: Request) -> Response {
-    let size = request
-        .headers()
-        .get("content-length")
-        .map(|v| v.to_str().unwrap().parse().unwrap());
+fn host(url: &str) -> Option<String> {
+    Url::parse(url).ok()?.host_str().map(str::to_string)
+}
+
+fn max_age(max_age: Duration) -> String {
+    format!("public, max-age={}", max_age.as_secs())
+}
+
+fn not_found(app: &App, decision: Decision) -> Response {
+    let outcome = Outcome {
+        decision: Some(decision),
+        ..Outcome::default()
+    };
+    // An origin that failed may still have the path, so nobody may cache
+    // the answer.
+    let cache_control = match decision {
+        Decision::PartialMiss => "no-store".to_string(),
+        _ => max_age(app.max_age(decision)),
+    };
+    (
+        StatusCode::NOT_FOUND,
+        [(header::CACHE_CONTROL, cache_control)],
+        Extension(outcome),
+    )
+        .into_response()
+}
+
+async fn upload(
+    State(app): State<AppState>,
+    ClientIp(client): ClientIp,
+    request: Request,
+) -> Result<Response, Error> {
+    let size = content_length(request.headers())?;
     let path = request.uri().path().to_string();
+    let content_type = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or(nix::content_type(&path))
+        .to_string();
     let body = request.into_body();
 
-    if let Err(err) = app.upload(&path, size, body.into_data_stream()).await {
-        tracing::error!("{} upload error: {:?}", path, err);
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    let write = app.write_config();
+    let res = if path.ends_with(".narinfo") && write.enabled && write.require_nar {
+        // Buffer the narinfo to find its NAR, so that clients never see a
+        // narinfo whose NAR is missing.
+        let Ok(narinfo) = axum::body::to_bytes(body, nix::MAX_NARINFO_SIZE).await else {
+            return Ok(StatusCode::PAYLOAD_TOO_LARGE.into_response());
+        };
+        if let Err(resp) = check_nar(&app, &narinfo).await {
+            return Ok(resp);
+        }
+        let size = narinfo.len() as u64;
+        let data = futures::stream::once(ready(Ok::<_, Infallible>(narinfo)));
+        app.upload(
+            &path,
+            Some(size),
+            &content_type,
+            &Provenance::client(),
+            data,
+        )
+        .await
+    } else {
+        let data = body.into_data_stream();
+        app.upload(&path, size, &content_type, &Provenance::client(), data)
+            .await
+    };
+    if let Err(err) = res {
+        tracing::error!("{} upload from {} error: {}", path, client, err);
+        return Ok(err.status().into_response());
     }
-    StatusCode::OK.into_response()
+    let outcome = Outcome {
+        upload: true,
+        ..Outcome::default()
+    };
+    Ok((StatusCode::OK, Extension(outcome)).into_response())
 }
 
-async fn delete(State(app): State<AppState>, request: Request) -> Response {
-    let path = request.uri().path().to_string();
-    if let Err(err) = app.delete(&path).await {
-        tracing::error!("{} delete error: {:?}", path, err);
-        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+/// Parses the `Content-Length` of an upload, if the client sent one.
+fn content_length(headers: &HeaderMap) -> Result<Option<u64>, Error> {
+    headers
+        .get(header::CONTENT_LENGTH)
+        .map(|v| {
+            v.to_str()
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .ok_or(Error::BadHeader("content-length"))
+        })
+        .transpose()
+}
+
+/// Rejects a narinfo whose NAR has not been uploaded yet.
+async fn check_nar(app: &App, narinfo: &[u8]) -> Result<(), Response> {
+    let Some(url) = nix::narinfo_url(narinfo) else {
+        return Err((StatusCode::BAD_REQUEST, "narinfo has no URL field\n").into_response());
+    };
+    match app
+        .exists(&format!("/{}", url.trim_start_matches('/')))
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((
+            StatusCode::CONFLICT,
+            format!("{url} has not been uploaded\n"),
+        )
+            .into_response()),
+        Err(err) => Err(err.into_response()),
+    }
+}
+
+async fn delete(
+    State(app): State<AppState>,
+    ClientIp(client): ClientIp,
+    request: Request,
+) -> StatusCode {
+    let path = request.uri().path();
+    if let Err(err) = app.delete(path).await {
+        tracing::error!("{} delete from {} error: {}", path, client, err);
+        return err.status();
+    }
+    StatusCode::OK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(content_length: &[u8]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = HeaderValue::from_bytes(content_length).unwrap();
+        headers.insert(header::CONTENT_LENGTH, value);
+        headers
+    }
+
+    #[test]
+    fn content_length_is_optional() {
+        assert_eq!(content_length(&HeaderMap::new()).unwrap(), None);
+        assert_eq!(content_length(&headers(b"0")).unwrap(), Some(0));
+        assert_eq!(
+            content_length(&headers(b"1048576")).unwrap(),
+            Some(1_048_576)
+        );
+    }
+
+    #[test]
+    fn malformed_content_length_is_bad_request() {
+        for value in [
+            &b"abc"[..],
+            b"",
+            b"-1",
+            b"1.5",
+            b"0x10",
+            b"1, 2",
+            b"99999999999999999999",
+            b"1\xff",
+        ] {
+            let err = content_length(&headers(value)).unwrap_err();
+            assert!(
+                matches!(err, Error::BadHeader("content-length")),
+                "{value:?}"
+            );
+            assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        }
     }
-    StatusCode::OK.into_response()
 }
@@ -0,0 +1,180 @@
+use serde::Deserialize;
+
+/// Settings for clients that upload with `nix copy --to`.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Write {
+    /// Answer HEAD from our S3 store only, so that `nix copy` uploads paths
+    /// that are merely available from mirrors or origins.
+    pub enabled: bool,
+    /// Reject a `.narinfo` PUT until the NAR it points to has been uploaded.
+    /// Only applies when `enabled` is set.
+    pub require_nar: bool,
+}
+
+impl Default for Write {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            require_nar: true,
+        }
+    }
+}
+
+/// Largest `.narinfo` accepted on PUT; real ones are a few kilobytes.
+pub const MAX_NARINFO_SIZE: usize = 1 << 20;
+
+/// Content type Nix uses for a file in a binary cache.
+pub fn content_type(path: &str) -> &'static str {
+    let path = path.trim_start_matches('/');
+    if path == "nix-cache-info" {
+        "text/x-nix-cache-info"
+    } else if path.starts_with("nar/") {
+        "application/x-nix-nar"
+    } else if path.starts_with("log/") {
+        "text/plain; charset=utf-8"
+    } else {
+        match path.rsplit_once('.').map(|(_, ext)| ext) {
+            Some("narinfo") => "text/x-nix-narinfo",
+            Some("ls") => "application/json",
+            _ => "application/octet-stream",
+        }
+    }
+}
+
+/// Whether `path` is a narinfo, which Nix looks up for every store path.
+pub fn is_narinfo(path: &str) -> bool {
+    content_type(path) == "text/x-nix-narinfo"
+}
+
+/// `Cache-Control` for the content of `path`. Store files never change once
+/// written, except that narinfos may gain signatures.
+pub fn cache_control(path: &str) -> &'static str {
+    match content_type(path) {
+        "text/x-nix-narinfo" | "text/x-nix-cache-info" => "public, max-age=3600",
+        _ => "public, max-age=31536000, immutable",
+    }
+}
+
+/// A strong `ETag` for NARs, whose file names are the hash of their content.
+pub fn etag(path: &str) -> Option<String> {
+    let name = path.trim_start_matches('/').strip_prefix("nar/")?;
+    let hash = name.split('.').next().filter(|h| !h.is_empty())?;
+    Some(format!("\"{hash}\""))
+}
+
+/// Returns the `URL:` field of a narinfo, the NAR path relative to the cache
+/// root.
+pub fn narinfo_url(narinfo: &[u8]) -> Option<&str> {
+    std::str::from_utf8(narinfo)
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("URL:"))
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0bpmnwjs5gf5yqwxkh5a9zrdc8a3pbi5";
+    const FILE_HASH: &str = "1w1fff338fvdw53sqgamddn1b2xgds473pv6y13gizdbqjv4i5p3";
+
+    #[test]
+    fn narinfo_urls() {
+        let nar = format!("nar/{FILE_HASH}.nar.xz");
+        for (narinfo, expected) in [
+            (
+                format!("StorePath: /nix/store/{HASH}-hello\nURL: {nar}\n"),
+                Some(nar.as_str()),
+            ),
+            (format!("URL:{nar}"), Some(nar.as_str())),
+            (
+                format!("URL:   {nar} \t\r\nCompression: xz\n"),
+                Some(nar.as_str()),
+            ),
+            (format!("URL: {nar}\r\n"), Some(nar.as_str())),
+            (
+                "StorePath: /nix/store/x-hello\nNarHash: sha256:x\n".to_owned(),
+                None,
+            ),
+            ("URL:\n".to_owned(), None),
+            ("URL:   \n".to_owned(), None),
+            (String::new(), None),
+            // The field name is case sensitive and must start the line.
+            (format!("url: {nar}\n"), None),
+            (format!(" URL: {nar}\n"), None),
+        ] {
+            assert_eq!(narinfo_url(narinfo.as_bytes()), expected, "{narinfo:?}");
+        }
+    }
+
+    #[test]
+    fn non_utf8_narinfos_have_no_url() {
+        let mut narinfo = format!("URL: nar/{FILE_HASH}.nar\n").into_bytes();
+        narinfo.extend_from_slice(b"Comment: \xff\xfe\n");
+        assert_eq!(narinfo_url(&narinfo), None);
+    }
+
+    #[test]
+    fn content_types() {
+        for (path, expected) in [
+            ("/nix-cache-info", "text/x-nix-cache-info"),
+            ("nix-cache-info", "text/x-nix-cache-info"),
+            (&format!("/{HASH}.narinfo"), "text/x-nix-narinfo"),
+            (&format!("/{HASH}.ls"), "application/json"),
+            (&format!("/nar/{FILE_HASH}.nar"), "application/x-nix-nar"),
+            (&format!("/nar/{FILE_HASH}.nar.xz"), "application/x-nix-nar"),
+            (
+                &format!("/nar/{FILE_HASH}.narinfo"),
+                "application/x-nix-nar",
+            ),
+            (
+                &format!("/log/{HASH}-hello.drv"),
+                "text/plain; charset=utf-8",
+            ),
+            (
+                &format!("/realisations/sha256:{FILE_HASH}!out.doi"),
+                "application/octet-stream",
+            ),
+            (&format!("/debuginfo/{HASH}"), "application/octet-stream"),
+            ("/", "application/octet-stream"),
+        ] {
+            assert_eq!(content_type(path), expected, "{path}");
+        }
+        assert!(is_narinfo(&format!("/{HASH}.narinfo")));
+        assert!(!is_narinfo(&format!("/nar/{FILE_HASH}.narinfo")));
+    }
+
+    #[test]
+    fn cache_controls() {
+        assert_eq!(cache_control("/nix-cache-info"), "public, max-age=3600");
+        assert_eq!(
+            cache_control(&format!("/{HASH}.narinfo")),
+            "public, max-age=3600"
+        );
+        for path in [format!("/nar/{FILE_HASH}.nar.xz"), format!("/{HASH}.ls")] {
+            assert_eq!(cache_control(&path), "public, max-age=31536000, immutable");
+        }
+    }
+
+    #[test]
+    fn etags() {
+        let quoted = format!("\"{FILE_HASH}\"");
+        for (path, expected) in [
+            (format!("/nar/{FILE_HASH}.nar"), Some(quoted.as_str())),
+            (format!("/nar/{FILE_HASH}.nar.xz"), Some(quoted.as_str())),
+            (format!("nar/{FILE_HASH}.nar.zst"), Some(quoted.as_str())),
+            (format!("/nar/{FILE_HASH}"), Some(quoted.as_str())),
+            ("/nar/.nar".to_owned(), None),
+            ("/nar/".to_owned(), None),
+            (format!("/{HASH}.narinfo"), None),
+            (format!("/{HASH}.ls"), None),
+            (format!("/log/{HASH}-hello.drv"), None),
+            ("/nix-cache-info".to_owned(), None),
+        ] {
+            assert_eq!(etag(&path).as_deref(), expected, "{path}");
+        }
+    }
+}
@@ -0,0 +1,480 @@
+use std::{
+    convert::Infallible,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, Request, State, connect_info::Connected},
+    http::{HeaderMap, request::Parts},
+    middleware::Next,
+    response::Response,
+    serve::{IncomingStream, Listener},
+};
+use ipnet::IpNet;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+
+use crate::AppState;
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Proxy {
+    /// Proxies whose `Forwarded`, `X-Forwarded-For` and PROXY protocol
+    /// headers are believed.
+    trusted: Vec<IpNet>,
+    /// Require a PROXY protocol v1 or v2 header on connections from trusted
+    /// proxies, except cluster peers, which forward requests directly.
+    proxy_protocol: bool,
+}
+
+impl Proxy {
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.trusted.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Walks the forwarding chain from the nearest hop, stopping at the first
+    /// address that is not a trusted proxy.
+    fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer.to_canonical();
+        if !self.is_trusted(client) {
+            return client;
+        }
+        let hops = forwarded_for(headers);
+        for hop in hops.into_iter().rev() {
+            if !self.is_trusted(client) {
+                break;
+            }
+            match hop {
+                Some(ip) => client = ip.to_canonical(),
+                None => break,
+            }
+        }
+        client
+    }
+}
+
+/// Hops listed in `Forwarded`, or `X-Forwarded-For` if there is none, from
+/// the original client to the nearest proxy. Obfuscated and unknown hops are
+/// `None`.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values("forwarded");
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(k, _)| k.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, v)| parse_node(v.trim_matches('"')))
+            })
+            .collect();
+    }
+    values("x-forwarded-for")
+        .into_iter()
+        .map(parse_node)
+        .collect()
+}
+
+/// Parses `1.2.3.4`, `1.2.3.4:80`, `::1` or `[::1]:80`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .and_then(|v| v.parse().ok())
+}
+
+/// Address of the client that sent the request, after trusted proxies.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<ClientIp>()
+            .copied()
+            .unwrap_or(ClientIp(IpAddr::V4(Ipv4Addr::UNSPECIFIED))))
+    }
+}
+
+/// Resolves [`ClientIp`] for every request.
+pub async fn resolve(
+    State(proxy): State<Arc<Proxy>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if let Some(ConnectInfo(PeerAddr(peer))) = request.extensions().get().copied() {
+        let ip = proxy.client_ip(peer.ip(), request.headers());
+        request.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(request).await
+}
+
+/// Address of the connection's peer, or the source address from its PROXY
+/// protocol header.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerAddr(pub SocketAddr);
+
+impl Connected<IncomingStream<'_, ProxyListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, ProxyListener>) -> Self {
+        PeerAddr(*stream.remote_addr())
+    }
+}
+
+/// Accepts TCP connections, reading PROXY protocol headers off connections
+/// from trusted proxies when enabled. Headers are read on separate tasks so
+/// that slow proxies do not hold up other connections.
+pub struct ProxyListener {
+    incoming: mpsc::Receiver<(BufReader<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl ProxyListener {
+    pub fn new(listener: TcpListener, proxy: Arc<Proxy>, app: AppState) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, incoming) = mpsc::channel(64);
+        tokio::spawn(accept_loop(listener, proxy, app, tx));
+        Ok(Self {
+            incoming,
+            local_addr,
+        })
+    }
+}
+
+impl Listener for ProxyListener {
+    type Io = BufReader<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.incoming.recv().await {
+            Some(v) => v,
+            // The accept loop only stops once this listener is dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    proxy: Arc<Proxy>,
+    app: AppState,
+    tx: mpsc::Sender<(BufReader<TcpStream>, SocketAddr)>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            v = listener.accept() => v,
+            () = tx.closed() => return,
+        };
+        let (stream, addr) = match accepted {
+            Ok(v) => v,
+            Err(err) => {
+                tracing::error!("accept error: {:?}", err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let mut stream = BufReader::new(stream);
+        let ip = addr.ip();
+        if !(proxy.proxy_protocol && proxy.is_trusted(ip)) || app.is_cluster_peer(ip) {
+            let _ = tx.send((stream, addr)).await;
+            continue;
+        }
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(Duration::from_secs(5), read_proxy_header(&mut stream)).await
+            {
+                Ok(Ok(source)) => {
+                    let _ = tx.send((stream, source.unwrap_or(addr))).await;
+                }
+                Ok(Err(err)) => tracing::warn!("{} invalid PROXY header: {:?}", addr, err),
+                Err(_) => tracing::warn!("{} timed out sending PROXY header", addr),
+            }
+        });
+    }
+}
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Consumes a PROXY protocol v1 or v2 header and returns the source address
+/// it carries, or `None` for `LOCAL`/`UNKNOWN` connections.
+async fn read_proxy_header<R: AsyncBufRead + Unpin>(
+    stream: &mut R,
+) -> anyhow::Result<Option<SocketAddr>> {
+    let mut head = [0; 16];
+    stream.read_exact(&mut head[..5]).await?;
+    if &head[..5] == b"PROXY" {
+        // v1 headers are at most 107 bytes including CRLF.
+        let mut line = Vec::new();
+        (&mut *stream)
+            .take(102)
+            .read_until(b'\n', &mut line)
+            .await?;
+        let line = std::str::from_utf8(&line)?
+            .strip_suffix("\r\n")
+            .ok_or_else(|| anyhow!("unterminated v1 header"))?;
+        let fields = line.split(' ').collect::<Vec<_>>();
+        return match fields[..] {
+            ["", "UNKNOWN", ..] => Ok(None),
+            ["", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+                Ok(Some(SocketAddr::new(src.parse()?, sport.parse()?)))
+            }
+            _ => bail!("malformed v1 header"),
+        };
+    }
+
+    stream.read_exact(&mut head[5..]).await?;
+    if &head[..12] != V2_SIGNATURE {
+        bail!("missing PROXY header");
+    }
+    if head[12] >> 4 != 2 {
+        bail!("unsupported version");
+    }
+    // LOCAL connections are health checks from the proxy itself.
+    let local = match head[12] & 0x0f {
+        0 => true,
+        1 => false,
+        command => bail!("unsupported command {command:#x}"),
+    };
+    let mut body = vec![0; usize::from(u16::from_be_bytes([head[14], head[15]]))];
+    stream.read_exact(&mut body).await?;
+    if local {
+        return Ok(None);
+    }
+    match head[13] >> 4 {
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&body[..4])?);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        2 if body.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16])?);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        1 | 2 => bail!("truncated v2 address"),
+        // Unspecified and unix socket addresses carry no client IP.
+        0 | 3 => Ok(None),
+        family => bail!("unsupported address family {family:#x}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn proxy(trusted: &[&str]) -> Proxy {
+        Proxy {
+            trusted: trusted.iter().map(|net| net.parse().unwrap()).collect(),
+            proxy_protocol: true,
+        }
+    }
+
+    fn header_map(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peers_are_the_client() {
+        let proxy = proxy(&["10.0.0.0/8"]);
+        let headers = header_map(&[("x-forwarded-for", "1.2.3.4")]);
+        assert_eq!(proxy.client_ip(ip("192.0.2.1"), &headers), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn chain_stops_at_first_untrusted_hop() {
+        let proxy = proxy(&["10.0.0.0/8"]);
+        // The leftmost entry is whatever the client claimed.
+        let headers = header_map(&[("x-forwarded-for", "6.6.6.6, 192.0.2.1, 10.0.0.2")]);
+        assert_eq!(proxy.client_ip(ip("10.0.0.1"), &headers), ip("192.0.2.1"));
+
+        let headers = header_map(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(proxy.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.3"));
+    }
+
+    #[test]
+    fn unknown_hops_stop_the_chain() {
+        let proxy = proxy(&["10.0.0.0/8"]);
+        let headers = header_map(&[("forwarded", "for=192.0.2.1, for=_hidden, for=10.0.0.2")]);
+        assert_eq!(proxy.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.2"));
+
+        let headers = header_map(&[("x-forwarded-for", "192.0.2.1, garbage")]);
+        assert_eq!(proxy.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn forwarded_takes_precedence_over_x_forwarded_for() {
+        let proxy = proxy(&["10.0.0.0/8"]);
+        let headers = header_map(&[
+            ("x-forwarded-for", "198.51.100.1"),
+            ("forwarded", "for=192.0.2.1;proto=https"),
+        ]);
+        assert_eq!(proxy.client_ip(ip("10.0.0.1"), &headers), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn ipv6_forwarded_values() {
+        let headers = header_map(&[
+            ("forwarded", r#"for="[2001:db8::1]:4711""#),
+            ("forwarded", r#"For="[2001:db8::2]";by=10.0.0.1"#),
+            ("forwarded", r#"proto=http;for="2001:db8::3""#),
+            ("forwarded", "for=[2001:db8::4"),
+        ]);
+        assert_eq!(
+            forwarded_for(&headers),
+            [
+                Some(ip("2001:db8::1")),
+                Some(ip("2001:db8::2")),
+                Some(ip("2001:db8::3")),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_canonical() {
+        let proxy = proxy(&["10.0.0.0/8"]);
+        let headers = header_map(&[("x-forwarded-for", "[::ffff:192.0.2.1]:80")]);
+        assert_eq!(
+            proxy.client_ip(ip("::ffff:10.0.0.1"), &headers),
+            ip("192.0.2.1")
+        );
+    }
+
+    async fn read(mut input: &[u8]) -> (anyhow::Result<Option<SocketAddr>>, &[u8]) {
+        let res = read_proxy_header(&mut input).await;
+        (res, input)
+    }
+
+    #[tokio::test]
+    async fn v1_headers() {
+        let (res, rest) = read(b"PROXY TCP4 192.0.2.1 10.0.0.1 4711 80\r\nGET /").await;
+        assert_eq!(res.unwrap(), Some("192.0.2.1:4711".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (res, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 80\r\n").await;
+        assert_eq!(res.unwrap(), Some("[2001:db8::1]:4711".parse().unwrap()));
+
+        let (res, _) = read(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(res.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn malformed_v1_headers() {
+        for input in [
+            &b"PROXY TCP4 192.0.2.1 10.0.0.1 4711 80"[..],
+            b"PROXY TCP4 192.0.2.1 10.0.0.1 4711 80\n",
+            b"PROXY TCP4 192.0.2.1 10.0.0.1 4711\r\n",
+            b"PROXY TCP4 192.0.2.999 10.0.0.1 4711 80\r\n",
+            b"PROXY TCP4 192.0.2.1 10.0.0.1 http 80\r\n",
+            b"PROXY TCP4  192.0.2.1 10.0.0.1 4711 80\r\n",
+            b"PROXY UDP4 192.0.2.1 10.0.0.1 4711 80\r\n",
+            b"PROX",
+            b"GET / HTTP/1.1\r\n\r\n",
+        ] {
+            assert!(read(input).await.0.is_err(), "{input:?}");
+        }
+        let long = format!("PROXY UNKNOWN {}\r\n", "x".repeat(100));
+        assert!(read(long.as_bytes()).await.0.is_err());
+    }
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([command, family]);
+        header.extend(u16::try_from(body.len()).unwrap().to_be_bytes());
+        header.extend(body);
+        header
+    }
+
+    const V2_TCP4: [u8; 12] = [192, 0, 2, 1, 10, 0, 0, 1, 0x12, 0x67, 0, 80];
+
+    #[tokio::test]
+    async fn v2_headers() {
+        let mut input = v2(0x21, 0x11, &V2_TCP4);
+        input.extend(b"GET /");
+        let (res, rest) = read(&input).await;
+        assert_eq!(res.unwrap(), Some("192.0.2.1:4711".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let mut tcp6 = [0; 36];
+        tcp6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        tcp6[32..34].copy_from_slice(&4711u16.to_be_bytes());
+        let (res, _) = read(&v2(0x21, 0x21, &tcp6)).await;
+        assert_eq!(res.unwrap(), Some("[2001:db8::1]:4711".parse().unwrap()));
+
+        // LOCAL and unspecified families carry no usable address, and any
+        // TLVs after the addresses are skipped.
+        let input = v2(0x20, 0x11, &V2_TCP4);
+        let (res, rest) = read(&input).await;
+        assert_eq!(res.unwrap(), None);
+        assert!(rest.is_empty());
+        let (res, _) = read(&v2(0x21, 0x00, &[])).await;
+        assert_eq!(res.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn malformed_v2_headers() {
+        // Commands other than LOCAL and PROXY, and versions other than 2.
+        for (command, family) in [(0x22, 0x11), (0x2f, 0x11), (0x11, 0x11), (0x31, 0x11)] {
+            let input = v2(command, family, &V2_TCP4);
+            assert!(read(&input).await.0.is_err(), "{command:#x}");
+        }
+        // Address blocks too short for their family, and unknown families.
+        for (family, body) in [
+            (0x11, &V2_TCP4[..8]),
+            (0x21, &V2_TCP4[..]),
+            (0x41, &V2_TCP4[..]),
+        ] {
+            let input = v2(0x21, family, body);
+            assert!(read(&input).await.0.is_err(), "{family:#x}");
+        }
+        // Truncated address block and header.
+        let mut input = v2(0x21, 0x11, &V2_TCP4);
+        input.truncate(20);
+        assert!(read(&input).await.0.is_err());
+        assert!(read(&V2_SIGNATURE[..10]).await.0.is_err());
+        // Bad signature.
+        let mut input = v2(0x21, 0x11, &V2_TCP4);
+        input[11] = b'X';
+        assert!(read(&input).await.0.is_err());
+    }
+}
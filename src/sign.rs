@@ -127,12 +127,7 @@ impl AwsSigner {
         let header_map = req
             .headers()
             .into_iter()
-            .map(|(k, v)| {
-                (
-                    k.as_str().to_lowercase(),
-                    v.to_str().map(str::trim).unwrap_or(""),
-                )
-            })
+            .map(|(k, v)| (k.as_str().to_lowercase(), v.to_str().map_or("", str::trim)))
             .collect::<BTreeMap<_, _>>();
 
         let signed_headers = header_map.keys().join(";");
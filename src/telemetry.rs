@@ -0,0 +1,117 @@
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Request};
+use opentelemetry::{
+    Context, global,
+    propagation::{Extractor, Injector},
+    trace::TracerProvider,
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource,
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracerProvider},
+};
+use serde::Deserialize;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt};
+
+#[derive(Deserialize)]
+pub struct Telemetry {
+    /// OTLP/HTTP traces endpoint, e.g. `http://localhost:4318/v1/traces`.
+    otlp_endpoint: String,
+    #[serde(default = "default_service_name")]
+    service_name: String,
+    /// Fraction of new traces to sample. Traces started upstream follow the
+    /// caller's sampling decision.
+    #[serde(default = "default_sample_ratio")]
+    sample_ratio: f64,
+}
+
+fn default_service_name() -> String {
+    "nix-store-gateway".to_string()
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+/// Installs the global subscriber, exporting spans over OTLP if configured.
+/// The returned provider must be shut down to flush pending spans.
+pub fn init(config: Option<&Telemetry>) -> anyhow::Result<Option<SdkTracerProvider>> {
+    let provider = config
+        .map(|c| -> anyhow::Result<_> {
+            let exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(&c.otlp_endpoint)
+                .build()?;
+            Ok(SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    c.sample_ratio,
+                ))))
+                .with_resource(
+                    Resource::builder()
+                        .with_service_name(c.service_name.clone())
+                        .build(),
+                )
+                .build())
+        })
+        .transpose()?;
+
+    let otel = provider.as_ref().map(|p| {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        tracing_opentelemetry::layer().with_tracer(p.tracer("nix-store-gateway"))
+    });
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(tracing_subscriber::fmt::layer())
+        .with(otel)
+        .init();
+    Ok(provider)
+}
+
+/// Creates the span for an incoming request, continuing the caller's trace
+/// if it sent a `traceparent` header.
+pub fn make_span<B>(request: &Request<B>) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+    );
+    let parent =
+        global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(request.headers())));
+    let _ = span.set_parent(parent);
+    span
+}
+
+/// Adds the current span's `traceparent` to an outgoing upstream request.
+pub fn inject(headers: &mut HeaderMap) {
+    let cx: Context = Span::current().context();
+    global::get_text_map_propagator(|p| p.inject_context(&cx, &mut HeaderInjector(headers)));
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(k), Ok(v)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(k, v);
+        }
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
@@ -0,0 +1,156 @@
+use std::{io::SeekFrom, path::PathBuf, sync::Arc, time::Duration};
+
+use metrics::counter;
+use serde::Deserialize;
+use tokio::{
+    fs::File,
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+
+/// What to do with a new background upload when the queue is full.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WhenFull {
+    /// Serve the client without caching the path.
+    Skip,
+    /// Hold the origin read until a queue slot frees up.
+    Wait,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Upload {
+    /// Maximum number of concurrent background PUTs to S3.
+    pub concurrency: usize,
+    /// Maximum number of background uploads queued or running.
+    pub queue: usize,
+    pub when_full: WhenFull,
+    /// Retries after the first attempt.
+    pub retries: u32,
+    /// Delay in seconds before the first retry, doubled after each attempt.
+    pub retry_backoff: u64,
+    /// Directory for spooling origin bodies before upload. Defaults to the
+    /// system temporary directory.
+    pub spool_dir: Option<PathBuf>,
+    /// Bodies larger than this many bytes are served but not cached.
+    pub max_size: u64,
+    /// Seconds to keep reading from the origin after the client disconnects.
+    pub orphan_timeout: u64,
+    /// Skip paths already in S3 and write with `If-None-Match: *`, so that
+    /// replicas sharing a bucket upload each path once. Off by default, as
+    /// not every S3-compatible store supports conditional writes.
+    pub conditional: bool,
+}
+
+impl Default for Upload {
+    fn default() -> Self {
+        Self {
+            concurrency: 16,
+            queue: 256,
+            when_full: WhenFull::Skip,
+            retries: 3,
+            retry_backoff: 1,
+            spool_dir: None,
+            max_size: 8 << 30,
+            orphan_timeout: 300,
+            conditional: false,
+        }
+    }
+}
+
+/// Bounds background origin-to-S3 uploads.
+pub struct UploadQueue {
+    config: Upload,
+    slots: Arc<Semaphore>,
+    workers: Arc<Semaphore>,
+}
+
+impl UploadQueue {
+    pub fn new(config: Upload) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(config.queue)),
+            workers: Arc::new(Semaphore::new(config.concurrency)),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &Upload {
+        &self.config
+    }
+
+    /// Reserves a queue slot according to the `when_full` policy and opens a
+    /// spool file for it. Returns `None` if the upload should be skipped.
+    pub async fn spool(&self) -> Option<Spool> {
+        let slot = match self.config.when_full {
+            WhenFull::Skip => self.slots.clone().try_acquire_owned().ok(),
+            WhenFull::Wait => self.slots.clone().acquire_owned().await.ok(),
+        };
+        let Some(slot) = slot else {
+            counter!("nix_store_gateway_upload_skipped", "reason" => "queue_full").increment(1);
+            return None;
+        };
+        let dir = self
+            .config
+            .spool_dir
+            .clone()
+            .unwrap_or_else(std::env::temp_dir);
+        match tokio::task::spawn_blocking(move || tempfile::tempfile_in(dir)).await {
+            Ok(Ok(file)) => Some(Spool {
+                file: File::from_std(file),
+                len: 0,
+                _slot: slot,
+            }),
+            Ok(Err(err)) => {
+                tracing::error!("cannot create spool file: {:?}", err);
+                counter!("nix_store_gateway_upload_skipped", "reason" => "spool_error")
+                    .increment(1);
+                None
+            }
+            Err(err) => {
+                tracing::error!("cannot create spool file: {:?}", err);
+                None
+            }
+        }
+    }
+
+    pub async fn worker(&self) -> OwnedSemaphorePermit {
+        self.workers
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("worker semaphore is never closed")
+    }
+
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        Duration::from_secs(self.config.retry_backoff).saturating_mul(1 << attempt.min(16))
+    }
+}
+
+/// An origin body buffered on disk so that the client and the S3 upload
+/// never wait on each other. The file is deleted when dropped.
+pub struct Spool {
+    file: File,
+    len: u64,
+    _slot: OwnedSemaphorePermit,
+}
+
+impl Spool {
+    pub async fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(buf).await?;
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns a handle reading the spooled body from the start.
+    pub async fn reader(&mut self) -> std::io::Result<File> {
+        self.file.flush().await?;
+        let mut file = self.file.try_clone().await?;
+        file.seek(SeekFrom::Start(0)).await?;
+        Ok(file)
+    }
+}